@@ -0,0 +1,102 @@
+use std::{
+	fs::OpenOptions,
+	io::{self, LineWriter, Write},
+	net::IpAddr,
+	sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, SyncSender, TrySendError}},
+	thread,
+	time::{SystemTime, UNIX_EPOCH},
+};
+use anyhow::{anyhow, Result};
+use jsonrpsee::{
+	core::Error as RpcError,
+	types::error::{CallError, CALL_EXECUTION_FAILED_CODE, INVALID_PARAMS_CODE, UNKNOWN_ERROR_CODE},
+};
+use serde::Serialize;
+use crate::context::Transport;
+use crate::metrics::ACCESS_LOG_DROPPED;
+
+#[derive(Serialize)]
+pub struct AccessLogEntry<'a> {
+	pub timestamp_ms: u64,
+	pub client_ip: Option<IpAddr>,
+	pub api_key: Option<&'a str>,
+	pub transport: Option<Transport>,
+	pub method: &'a str,
+	pub http_status: Option<u16>,
+	pub error_code: Option<i32>,
+	pub latency_ms: f64,
+	/// Unset when the response was served from cache.
+	pub upstream: Option<&'a str>,
+	pub response_bytes: usize,
+}
+
+impl AccessLogEntry<'_> {
+	pub fn now_ms() -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+	}
+}
+
+/// Writes one JSON line per call to a file, or to stdout for `-`. Lines are written on a background thread so calls
+/// never block on the output, when its queue is full lines are dropped and counted instead.
+pub struct AccessLog {
+	lines: SyncSender<String>,
+	dropping: AtomicBool,
+}
+
+impl AccessLog {
+	const QUEUE_CAPACITY: usize = 8192;
+
+	pub fn open(path: &str) -> Result<Self> {
+		let out: Box<dyn Write + Send> = match path {
+			"-" => Box::new(LineWriter::new(io::stdout())),
+			_ => Box::new(LineWriter::new(
+				OpenOptions::new().create(true).append(true).open(path)
+					.map_err(|e| anyhow!("failed opening access log {}: {}", path, e))?,
+			)),
+		};
+		let (lines, rx) = mpsc::sync_channel::<String>(Self::QUEUE_CAPACITY);
+		thread::Builder::new()
+			.name("access-log".to_string())
+			.spawn(move || {
+				let mut out = out;
+				for line in rx {
+					if let Err(e) = writeln!(out, "{}", line) {
+						tracing::warn!("failed writing access log: {}", e);
+					}
+				}
+			})
+			.map_err(|e| anyhow!("failed starting access log writer: {}", e))?;
+		Ok(Self { lines, dropping: AtomicBool::new(false) })
+	}
+
+	pub fn write(&self, entry: &AccessLogEntry) {
+		let line = match serde_json::to_string(entry) {
+			Ok(line) => line,
+			Err(e) => return tracing::warn!("failed serializing access log entry: {}", e),
+		};
+		match self.lines.try_send(line) {
+			Ok(()) => {
+				if self.dropping.swap(false, Ordering::Relaxed) {
+					tracing::info!("access log writer caught up");
+				}
+			},
+			Err(TrySendError::Full(_)) => {
+				ACCESS_LOG_DROPPED.inc();
+				if !self.dropping.swap(true, Ordering::Relaxed) {
+					tracing::warn!("access log writer is falling behind, dropping lines");
+				}
+			},
+			Err(TrySendError::Disconnected(_)) => tracing::warn!("access log writer stopped"),
+		}
+	}
+}
+
+/// JSON-RPC error code the client receives for `err`, matching jsonrpsee's conversion into an error object.
+pub fn error_code(err: &RpcError) -> i32 {
+	match err {
+		RpcError::Call(CallError::Custom(e)) => e.code(),
+		RpcError::Call(CallError::InvalidParams(_)) => INVALID_PARAMS_CODE,
+		RpcError::Call(CallError::Failed(_)) => CALL_EXECUTION_FAILED_CODE,
+		_ => UNKNOWN_ERROR_CODE,
+	}
+}
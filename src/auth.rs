@@ -0,0 +1,519 @@
+use std::{collections::{HashMap, HashSet}, fs, sync::Arc};
+use anyhow::{anyhow, Result};
+use hyper::{Body, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use crate::comet34::parse_height;
+use crate::config::DEFAULT_RATE_LIMIT_BURST;
+use crate::query::{validate_tx_hash, Condition, Operand, Operator, Query};
+use crate::ratelimit::RateLimiter;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const API_KEY_QUERY_PARAM: &str = "api_key";
+pub const INVALID_API_KEY_CODE: i32 = -32011;
+
+/// Reads the API key from the `x-api-key` header, falling back to the `api_key` query param.
+pub fn api_key_from_request(req: &Request<Body>) -> Option<String> {
+	req.headers()
+		.get(API_KEY_HEADER)
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.to_string())
+		.or_else(|| req.uri().query().and_then(|q| url::form_urlencoded::parse(q.as_bytes())
+			.find(|(k, _)| k == API_KEY_QUERY_PARAM)
+			.map(|(_, v)| v.into_owned())
+		))
+}
+
+#[derive(Clone, Default)]
+pub struct AccessPolicy {
+	pub allowed_routes: Option<HashSet<String>>,
+	pub blocked_routes: HashSet<String>,
+}
+
+impl AccessPolicy {
+	pub fn allows(&self, route: &str) -> bool {
+		!self.blocked_routes.contains(route) && self.allowed_routes.as_ref().is_none_or(|a| a.contains(route))
+	}
+
+	/// Errors on allowed routes that don't exist or are also blocked, both of which are most likely config mistakes.
+	pub fn validate(&self, mut is_route: impl FnMut(&str) -> bool) -> Result<()> {
+		for route in self.allowed_routes.iter().flatten() {
+			if !is_route(route) {
+				return Err(anyhow!("unknown route in allowed routes: {}", route));
+			}
+			if self.blocked_routes.contains(route) {
+				return Err(anyhow!("route is both allowed and blocked: {}", route));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Checks what every policy enforces on a condition: an allowed operator and well-formed values for known keys.
+fn check_condition(condition: &Condition, operators: &HashSet<Operator>) -> Result<()> {
+	if !operators.contains(&condition.operator) {
+		return Err(anyhow!("unsupported operator for {}: {}", condition.key, condition.operator));
+	}
+	match (condition.key.as_str(), &condition.operand) {
+		("tx.hash", Some(Operand::String(hash))) => validate_tx_hash(hash),
+		("tx.hash", Some(_)) => Err(anyhow!("tx.hash must be a quoted string")),
+		("tx.height" | "block.height", Some(operand)) if operand.as_u64().is_none() => {
+			Err(anyhow!("{} must be a non-negative integer", condition.key))
+		},
+		_ => Ok(()),
+	}
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TxSearchPolicy {
+	pub query_keys: HashSet<String>,
+	pub operators: HashSet<Operator>,
+	pub max_conditions: usize,
+	pub max_query_length: usize,
+	pub allow_prove: bool,
+}
+
+impl Default for TxSearchPolicy {
+	fn default() -> Self {
+		Self {
+			query_keys: HashSet::from(["tx.hash".to_string()]),
+			operators: HashSet::from([Operator::Eq]),
+			max_conditions: 4,
+			max_query_length: 512,
+			allow_prove: false,
+		}
+	}
+}
+
+/// Parses a search query and errors unless it is small enough and every condition uses an allowed key and operator.
+fn check_search_query(
+	query: &str,
+	max_query_length: usize,
+	max_conditions: usize,
+	keys: &HashSet<String>,
+	operators: &HashSet<Operator>,
+) -> Result<Query> {
+	if query.len() > max_query_length {
+		return Err(anyhow!("query exceeds {} characters", max_query_length));
+	}
+	let query = Query::parse(query)?;
+	if query.conditions.len() > max_conditions {
+		return Err(anyhow!("query exceeds {} conditions", max_conditions));
+	}
+	for condition in &query.conditions {
+		if !keys.contains(&condition.key) {
+			return Err(anyhow!("unsupported query key: {}", condition.key));
+		}
+		check_condition(condition, operators)?;
+	}
+	Ok(query)
+}
+
+impl TxSearchPolicy {
+	pub fn check(&self, query: &str) -> Result<Query> {
+		check_search_query(query, self.max_query_length, self.max_conditions, &self.query_keys, &self.operators)
+	}
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockSearchPolicy {
+	pub query_keys: HashSet<String>,
+	pub operators: HashSet<Operator>,
+	pub max_conditions: usize,
+	pub max_query_length: usize,
+	pub max_height_window: u64,
+}
+
+impl Default for BlockSearchPolicy {
+	fn default() -> Self {
+		Self {
+			query_keys: HashSet::from(["block.height".to_string()]),
+			operators: HashSet::from([Operator::Eq, Operator::Lt, Operator::Le, Operator::Gt, Operator::Ge]),
+			max_conditions: 4,
+			max_query_length: 512,
+			max_height_window: 10000,
+		}
+	}
+}
+
+impl BlockSearchPolicy {
+	/// Besides keys and operators, requires `block.height` bounded on both sides within `max_height_window` blocks, so
+	/// no query scans the whole event index. A window of 0 lifts that requirement except for `CONTAINS` queries.
+	pub fn check(&self, query: &str) -> Result<Query> {
+		let query = check_search_query(query, self.max_query_length, self.max_conditions, &self.query_keys, &self.operators)?;
+		let (mut from, mut to) = (None::<u64>, None::<u64>);
+		for condition in query.conditions_on("block.height") {
+			let Some(height) = condition.operand.as_ref().and_then(Operand::as_u64) else {
+				continue;
+			};
+			let (lower, upper) = match condition.operator {
+				Operator::Eq => (Some(height), Some(height)),
+				Operator::Gt => (Some(height.checked_add(1).ok_or_else(no_heights)?), None),
+				Operator::Ge => (Some(height), None),
+				Operator::Lt => (None, Some(height.checked_sub(1).ok_or_else(no_heights)?)),
+				Operator::Le => (None, Some(height)),
+				_ => (None, None),
+			};
+			from = from.max(lower);
+			to = match (to, upper) {
+				(Some(to), Some(upper)) => Some(to.min(upper)),
+				(to, upper) => to.or(upper),
+			};
+		}
+		let window = match (from, to) {
+			(Some(from), Some(to)) if from > to => return Err(no_heights()),
+			(Some(from), Some(to)) => Some((to - from).saturating_add(1)),
+			_ => None,
+		};
+		if window.is_none() && query.conditions.iter().any(|c| c.operator == Operator::Contains) {
+			return Err(anyhow!("CONTAINS queries must bound block.height from both sides"));
+		}
+		if self.max_height_window > 0 {
+			match window {
+				None => return Err(anyhow!("query must bound block.height from both sides")),
+				Some(window) if window > self.max_height_window => {
+					return Err(anyhow!("block.height window exceeds {} blocks", self.max_height_window));
+				},
+				_ => {},
+			}
+		}
+		Ok(query)
+	}
+}
+
+fn no_heights() -> anyhow::Error {
+	anyhow!("block.height bounds match no blocks")
+}
+
+/// Bounds on how deep `tx_search` and `block_search` results can be paged through.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaginationPolicy {
+	pub max_page: u64,
+	pub max_per_page: u64,
+}
+
+impl Default for PaginationPolicy {
+	fn default() -> Self {
+		Self {
+			max_page: 10,
+			max_per_page: 30,
+		}
+	}
+}
+
+impl PaginationPolicy {
+	pub fn check(&self, page: &JsonValue, per_page: &JsonValue, order_by: &JsonValue) -> Result<()> {
+		let within = |value: &JsonValue, max: u64| value.is_null() || parse_height(value).is_some_and(|v| (1..=max).contains(&v));
+		if !within(page, self.max_page) {
+			return Err(anyhow!("page must be between 1 and {}", self.max_page));
+		}
+		if !within(per_page, self.max_per_page) {
+			return Err(anyhow!("per_page must be between 1 and {}", self.max_per_page));
+		}
+		let order_by_valid = match order_by {
+			JsonValue::Null => true,
+			JsonValue::String(o) => matches!(o.trim_matches('"'), "" | "asc" | "desc"),
+			_ => false,
+		};
+		if !order_by_valid {
+			return Err(anyhow!("order_by must be asc or desc"));
+		}
+		Ok(())
+	}
+}
+
+/// Query keys a subscription to an event type may filter on, beyond `tm.event`.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventPolicy {
+	pub query_keys: HashSet<String>,
+	pub required_keys: HashSet<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionPolicy {
+	pub events: HashMap<String, EventPolicy>,
+	pub operators: HashSet<Operator>,
+	pub max_query_length: usize,
+}
+
+impl Default for SubscriptionPolicy {
+	fn default() -> Self {
+		let tx_hash = HashSet::from(["tx.hash".to_string()]);
+		Self {
+			events: HashMap::from([
+				("NewBlock".to_string(), EventPolicy::default()),
+				("NewBlockHeader".to_string(), EventPolicy::default()),
+				("Tx".to_string(), EventPolicy { query_keys: tx_hash.clone(), required_keys: tx_hash }),
+			]),
+			operators: HashSet::from([Operator::Eq]),
+			max_query_length: 256,
+		}
+	}
+}
+
+impl SubscriptionPolicy {
+	/// Errors unless the query selects one allowed event type and only filters on keys allowed for it.
+	pub fn check(&self, query: &str) -> Result<Query> {
+		if query.len() > self.max_query_length {
+			return Err(anyhow!("query exceeds {} characters", self.max_query_length));
+		}
+		let query = Query::parse(query)?;
+		let event_type = match query.conditions_on("tm.event").collect::<Vec<_>>().as_slice() {
+			[Condition { operator: Operator::Eq, operand: Some(Operand::String(event_type)), .. }] => event_type.clone(),
+			_ => return Err(anyhow!("query must select exactly one tm.event")),
+		};
+		let policy = self.events.get(&event_type).ok_or_else(|| anyhow!("unsupported event type: {}", event_type))?;
+		for condition in query.conditions.iter().filter(|c| c.key != "tm.event") {
+			if !policy.query_keys.contains(&condition.key) && !policy.required_keys.contains(&condition.key) {
+				return Err(anyhow!("unsupported query key for {}: {}", event_type, condition.key));
+			}
+			check_condition(condition, &self.operators)?;
+		}
+		for key in &policy.required_keys {
+			if query.conditions_on(key).next().is_none() {
+				return Err(anyhow!("{} subscriptions must filter on {}", event_type, key));
+			}
+		}
+		Ok(query)
+	}
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+	pub key: String,
+	pub name: String,
+	#[serde(default)]
+	pub allowed_routes: Option<HashSet<String>>,
+	#[serde(default)]
+	pub blocked_routes: HashSet<String>,
+	#[serde(default)]
+	pub rate_limit_per_second: u32,
+	#[serde(default = "default_rate_limit_burst")]
+	pub rate_limit_burst: u32,
+	#[serde(default)]
+	pub tx_search: TxSearchPolicy,
+	#[serde(default)]
+	pub block_search: BlockSearchPolicy,
+	#[serde(default)]
+	pub subscribe: SubscriptionPolicy,
+	#[serde(default)]
+	pub pagination: PaginationPolicy,
+}
+
+impl ApiKeyConfig {
+	/// Reads a JSON array of API keys.
+	pub fn from_file(path: &str) -> Result<Vec<Self>> {
+		let content = fs::read_to_string(path).map_err(|e| anyhow!("failed reading API keys file {}: {}", path, e))?;
+		serde_json::from_str(&content).map_err(|e| anyhow!("failed parsing API keys file {}: {}", path, e))
+	}
+}
+
+fn default_rate_limit_burst() -> u32 {
+	DEFAULT_RATE_LIMIT_BURST
+}
+
+pub struct ApiKey {
+	pub name: String,
+	pub policy: AccessPolicy,
+	pub rate_limiter: Arc<RateLimiter<()>>,
+	pub tx_search: TxSearchPolicy,
+	pub block_search: BlockSearchPolicy,
+	pub subscribe: SubscriptionPolicy,
+	pub pagination: PaginationPolicy,
+}
+
+pub struct ApiKeys {
+	keys: HashMap<String, ApiKey>,
+}
+
+impl ApiKeys {
+	/// Builds the keys from `configs`, keeping the rate limit buckets of `previous` keys whose limits did not change.
+	pub fn new(configs: Vec<ApiKeyConfig>, route_costs: &HashMap<String, u32>, previous: Option<&ApiKeys>) -> Result<Self> {
+		let mut keys = HashMap::new();
+		for config in configs {
+			let previous_limiter = previous.and_then(|p| p.keys.get(&config.key)).map(|k| &k.rate_limiter);
+			let key = ApiKey {
+				name: config.name,
+				policy: AccessPolicy {
+					allowed_routes: config.allowed_routes,
+					blocked_routes: config.blocked_routes,
+				},
+				rate_limiter: RateLimiter::new_or_reuse(
+					config.rate_limit_per_second,
+					config.rate_limit_burst,
+					route_costs.clone(),
+					previous_limiter,
+				),
+				tx_search: config.tx_search,
+				block_search: config.block_search,
+				subscribe: config.subscribe,
+				pagination: config.pagination,
+			};
+			key.rate_limiter.validate().map_err(|e| anyhow!("API key {}: {}", key.name, e))?;
+			if keys.insert(config.key, key).is_some() {
+				return Err(anyhow!("duplicate API key"));
+			}
+		}
+		Ok(Self { keys })
+	}
+
+	pub fn get(&self, key: &str) -> Option<&ApiKey> {
+		self.keys.get(key)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &ApiKey> {
+		self.keys.values()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn policy(max_height_window: u64) -> BlockSearchPolicy {
+		BlockSearchPolicy {
+			query_keys: HashSet::from(["block.height".to_string(), "begin_block.rewards".to_string()]),
+			operators: HashSet::from([Operator::Eq, Operator::Lt, Operator::Le, Operator::Gt, Operator::Ge, Operator::Contains]),
+			max_conditions: 4,
+			max_query_length: 512,
+			max_height_window,
+		}
+	}
+
+	#[test]
+	fn block_search_accepts_bounded_windows() {
+		let policy = policy(10);
+		assert!(policy.check("block.height = 5").is_ok());
+		assert!(policy.check("block.height > 4 AND block.height < 15").is_ok());
+		assert!(policy.check("block.height >= 5 AND block.height <= 14").is_ok());
+		assert!(policy.check("block.height >= 1 AND block.height <= 100 AND block.height = 50").is_ok());
+	}
+
+	#[test]
+	fn block_search_rejects_windows_past_the_limit() {
+		let policy = policy(10);
+		assert!(policy.check("block.height >= 5 AND block.height <= 15").is_err());
+		assert!(policy.check("block.height > 4 AND block.height < 16").is_err());
+		assert!(policy.check("block.height >= 0 AND block.height <= 18446744073709551615").is_err());
+	}
+
+	#[test]
+	fn block_search_requires_both_bounds() {
+		let policy = policy(10);
+		assert!(policy.check("block.height > 5").is_err());
+		assert!(policy.check("block.height <= 5").is_err());
+		assert!(policy.check("block.height > 5 AND block.height >= 7").is_err());
+	}
+
+	#[test]
+	fn block_search_rejects_contradictory_bounds() {
+		for max_height_window in [0, 10] {
+			let policy = policy(max_height_window);
+			assert!(policy.check("block.height > 100 AND block.height < 50").is_err());
+			assert!(policy.check("block.height = 5 AND block.height = 6").is_err());
+			assert!(policy.check("block.height < 0").is_err());
+			assert!(policy.check("block.height > 18446744073709551615").is_err());
+		}
+	}
+
+	#[test]
+	fn block_search_handles_saturating_edges() {
+		let policy = policy(10);
+		assert!(policy.check("block.height >= 18446744073709551610 AND block.height <= 18446744073709551615").is_ok());
+		assert!(policy.check("block.height < 1 AND block.height >= 0").is_ok());
+		assert!(policy.check("block.height = 18446744073709551616").is_err());
+	}
+
+	#[test]
+	fn block_search_without_window_limit() {
+		let policy = policy(0);
+		assert!(policy.check("block.height > 5").is_ok());
+		assert!(policy.check("block.height >= 0 AND block.height <= 18446744073709551615").is_ok());
+		assert!(policy.check("begin_block.rewards CONTAINS 'stake'").is_err());
+		assert!(policy.check("begin_block.rewards CONTAINS 'stake' AND block.height > 5").is_err());
+		assert!(policy.check("begin_block.rewards CONTAINS 'stake' AND block.height > 5 AND block.height < 1000000").is_ok());
+	}
+
+	#[test]
+	fn block_search_checks_keys_and_operators() {
+		let policy = BlockSearchPolicy::default();
+		assert!(policy.check("block.height >= 5 AND block.height <= 14").is_ok());
+		assert!(policy.check("block.height >= 5 AND block.height <= 14 AND begin_block.rewards CONTAINS 'x'").is_err());
+		assert!(policy.check("block.height EXISTS").is_err());
+		assert!(policy.check("block.height = -1").is_err());
+	}
+
+	#[test]
+	fn pagination_bounds_page_and_per_page() {
+		let policy = PaginationPolicy::default();
+		assert!(policy.check(&json!(null), &json!(null), &json!(null)).is_ok());
+		assert!(policy.check(&json!(1), &json!(1), &json!(null)).is_ok());
+		assert!(policy.check(&json!(10), &json!(30), &json!(null)).is_ok());
+		assert!(policy.check(&json!(0), &json!(null), &json!(null)).is_err());
+		assert!(policy.check(&json!(11), &json!(null), &json!(null)).is_err());
+		assert!(policy.check(&json!(null), &json!(0), &json!(null)).is_err());
+		assert!(policy.check(&json!(null), &json!(31), &json!(null)).is_err());
+		assert!(policy.check(&json!(-1), &json!(null), &json!(null)).is_err());
+		assert!(policy.check(&json!(1.5), &json!(null), &json!(null)).is_err());
+	}
+
+	#[test]
+	fn pagination_accepts_strings() {
+		let policy = PaginationPolicy::default();
+		assert!(policy.check(&json!("10"), &json!("30"), &json!(null)).is_ok());
+		assert!(policy.check(&json!("\"2\""), &json!("\"5\""), &json!(null)).is_ok());
+		assert!(policy.check(&json!("11"), &json!(null), &json!(null)).is_err());
+		assert!(policy.check(&json!(null), &json!("31"), &json!(null)).is_err());
+		assert!(policy.check(&json!("first"), &json!(null), &json!(null)).is_err());
+		assert!(policy.check(&json!(null), &json!(""), &json!(null)).is_err());
+	}
+
+	#[test]
+	fn pagination_checks_order_by() {
+		let policy = PaginationPolicy::default();
+		for order_by in [json!(null), json!(""), json!("asc"), json!("desc"), json!("\"desc\"")] {
+			assert!(policy.check(&json!(null), &json!(null), &order_by).is_ok());
+		}
+		for order_by in [json!("ASC"), json!("random"), json!(1), json!(true)] {
+			assert!(policy.check(&json!(null), &json!(null), &order_by).is_err());
+		}
+	}
+
+	#[test]
+	fn subscribe_requires_one_allowed_event_type() {
+		let policy = SubscriptionPolicy::default();
+		assert!(policy.check("tm.event = 'NewBlock'").is_ok());
+		assert!(policy.check("tm.event='NewBlockHeader'").is_ok());
+		assert!(policy.check("tm.event = 'Vote'").is_err());
+		assert!(policy.check("tm.event = 'NewBlock' AND tm.event = 'NewBlockHeader'").is_err());
+		assert!(policy.check("tm.event CONTAINS 'NewBlock'").is_err());
+		assert!(policy.check("tm.event EXISTS").is_err());
+		assert!(policy.check("tx.hash = 'AB'").is_err());
+	}
+
+	#[test]
+	fn subscribe_checks_event_keys() {
+		let policy = SubscriptionPolicy::default();
+		let hash = "AB".repeat(32);
+		assert!(policy.check(&format!("tm.event = 'Tx' AND tx.hash = '{}'", hash)).is_ok());
+		assert!(policy.check("tm.event = 'Tx'").is_err());
+		assert!(policy.check("tm.event = 'Tx' AND tx.hash = 'xyz'").is_err());
+		assert!(policy.check(&format!("tm.event = 'Tx' AND tx.hash = '{}' AND message.sender = 'a'", hash)).is_err());
+		assert!(policy.check(&format!("tm.event = 'NewBlock' AND tx.hash = '{}'", hash)).is_err());
+		assert!(policy.check(&format!("tm.event = 'Tx' AND tx.hash > '{}'", hash)).is_err());
+	}
+
+	#[test]
+	fn subscribe_limits_query_length() {
+		let policy = SubscriptionPolicy { max_query_length: 21, ..SubscriptionPolicy::default() };
+		assert!(policy.check("tm.event = 'NewBlock'").is_ok());
+		assert!(policy.check("tm.event = 'NewBlock' ").is_err());
+	}
+}
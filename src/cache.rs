@@ -0,0 +1,234 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use jsonrpsee::{core::Error as RpcError, types::{error::CallError, ErrorObjectOwned}};
+use lru::LruCache;
+use serde_json::Value as JsonValue;
+use tokio::sync::OnceCell;
+
+struct CacheEntries {
+	lru: LruCache<String, Vec<u8>>,
+	used_bytes: usize,
+}
+
+pub struct ResponseCache {
+	entries: Mutex<CacheEntries>,
+	pub max_bytes: usize,
+}
+
+impl ResponseCache {
+	pub fn new(max_bytes: usize) -> Self {
+		Self {
+			entries: Mutex::new(CacheEntries { lru: LruCache::unbounded(), used_bytes: 0 }),
+			max_bytes,
+		}
+	}
+
+	pub fn get(&self, key: &str) -> Option<JsonValue> {
+		let mut entries = self.entries.lock().expect("cache lock poisoned");
+		entries.lru.get(key).and_then(|bytes| serde_json::from_slice(bytes).ok())
+	}
+
+	/// Inserts a response as serialized bytes, evicting least recently used entries until it fits in `max_bytes`.
+	pub fn insert(&self, key: String, value: &JsonValue) {
+		let Ok(bytes) = serde_json::to_vec(value) else {
+			return;
+		};
+		let size = key.len() + bytes.len();
+		if size > self.max_bytes {
+			return;
+		}
+		let mut entries = self.entries.lock().expect("cache lock poisoned");
+		if let Some(old) = entries.lru.pop(&key) {
+			entries.used_bytes -= key.len() + old.len();
+		}
+		while entries.used_bytes + size > self.max_bytes {
+			match entries.lru.pop_lru() {
+				Some((evicted_key, evicted)) => entries.used_bytes -= evicted_key.len() + evicted.len(),
+				None => break,
+			}
+		}
+		entries.lru.put(key, bytes);
+		entries.used_bytes += size;
+	}
+}
+
+type SharedResult = Result<JsonValue, ErrorObjectOwned>;
+
+pub struct TtlCache {
+	ttls: HashMap<String, Duration>,
+	entries: Mutex<HashMap<String, (Instant, JsonValue)>>,
+	in_flight: Mutex<HashMap<String, Arc<OnceCell<SharedResult>>>>,
+}
+
+impl TtlCache {
+	pub fn new(ttls_ms: &HashMap<String, u64>) -> Self {
+		Self {
+			ttls: ttls_ms.iter().map(|(method, ms)| (method.clone(), Duration::from_millis(*ms))).collect(),
+			entries: Mutex::new(HashMap::new()),
+			in_flight: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Serves `method` from the cache while fresh, otherwise shares a single `fetch` between all concurrent identical calls.
+	/// Methods without a configured TTL are fetched directly.
+	pub async fn fetch(
+		&self,
+		method: &str,
+		params: &[JsonValue],
+		fetch: impl Future<Output = Result<JsonValue, RpcError>>,
+	) -> Result<JsonValue, RpcError> {
+		let ttl = match self.ttls.get(method) {
+			Some(ttl) => *ttl,
+			None => return fetch.await,
+		};
+		let key = cache_key(method, params);
+		if let Some((expires_at, value)) = self.entries.lock().expect("cache lock poisoned").get(&key) {
+			if *expires_at > Instant::now() {
+				return Ok(value.clone());
+			}
+		}
+		let cell = self.in_flight.lock().expect("cache lock poisoned")
+			.entry(key.clone())
+			.or_default()
+			.clone();
+		let res = cell.get_or_init(|| async {
+			let res = fetch.await.map_err(ErrorObjectOwned::from);
+			if let Ok(value) = &res {
+				let now = Instant::now();
+				let mut entries = self.entries.lock().expect("cache lock poisoned");
+				entries.retain(|_, (expires_at, _)| *expires_at > now);
+				entries.insert(key.clone(), (now + ttl, value.clone()));
+			}
+			self.in_flight.lock().expect("cache lock poisoned").remove(&key);
+			res
+		}).await;
+		res.clone().map_err(|e| RpcError::Call(CallError::Custom(e)))
+	}
+}
+
+/// Builds a cache key from the method and its positional params, treating numeric strings and numbers alike.
+pub fn cache_key(method: &str, params: &[JsonValue]) -> String {
+	let normalized = params.iter()
+		.map(|p| match p {
+			JsonValue::String(s) => s.trim_matches('"').parse::<u64>().map(JsonValue::from).unwrap_or_else(|_| p.clone()),
+			_ => p.clone(),
+		})
+		.collect::<Vec<JsonValue>>();
+	format!("{}:{}", method, JsonValue::Array(normalized))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use futures_util::future::join_all;
+	use serde_json::json;
+	use tokio::time::sleep;
+
+	fn used_bytes(cache: &ResponseCache) -> usize {
+		cache.entries.lock().unwrap().used_bytes
+	}
+
+	fn entry_size(key: &str, value: &JsonValue) -> usize {
+		key.len() + serde_json::to_vec(value).unwrap().len()
+	}
+
+	#[test]
+	fn insert_counts_serialized_bytes() {
+		let cache = ResponseCache::new(1024);
+		let value = json!({"height": "10", "hash": "ABCD"});
+		cache.insert("block:[10]".to_string(), &value);
+		assert_eq!(used_bytes(&cache), entry_size("block:[10]", &value));
+		assert_eq!(cache.get("block:[10]"), Some(value));
+	}
+
+	#[test]
+	fn insert_evicts_least_recently_used() {
+		let value = json!("0123456789");
+		let size = entry_size("a", &value);
+		let cache = ResponseCache::new(size * 2);
+		cache.insert("a".to_string(), &value);
+		cache.insert("b".to_string(), &value);
+		assert!(cache.get("a").is_some());
+		cache.insert("c".to_string(), &value);
+		assert!(cache.get("a").is_some());
+		assert!(cache.get("b").is_none());
+		assert!(cache.get("c").is_some());
+		assert_eq!(used_bytes(&cache), size * 2);
+	}
+
+	#[test]
+	fn insert_replaces_existing_key() {
+		let cache = ResponseCache::new(1024);
+		cache.insert("a".to_string(), &json!("a much longer value than the next one"));
+		let value = json!("short");
+		cache.insert("a".to_string(), &value);
+		assert_eq!(used_bytes(&cache), entry_size("a", &value));
+		assert_eq!(cache.get("a"), Some(value));
+	}
+
+	#[test]
+	fn insert_skips_oversized_values() {
+		let cache = ResponseCache::new(8);
+		cache.insert("a".to_string(), &json!("0123456789"));
+		assert!(cache.get("a").is_none());
+		assert_eq!(used_bytes(&cache), 0);
+	}
+
+	fn ttl_cache(ms: u64) -> TtlCache {
+		TtlCache::new(&HashMap::from([("status".to_string(), ms)]))
+	}
+
+	async fn fetch(calls: &AtomicUsize, res: Result<JsonValue, RpcError>) -> Result<JsonValue, RpcError> {
+		calls.fetch_add(1, Ordering::SeqCst);
+		sleep(Duration::from_millis(20)).await;
+		res
+	}
+
+	#[tokio::test]
+	async fn fetch_coalesces_concurrent_calls() {
+		let cache = ttl_cache(60_000);
+		let calls = AtomicUsize::new(0);
+		let results = join_all((0..5).map(|_| cache.fetch("status", &[], fetch(&calls, Ok(json!({"height": "10"})))))).await;
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+		assert!(results.iter().all(|r| r.as_ref().unwrap() == &json!({"height": "10"})));
+		cache.fetch("status", &[], fetch(&calls, Ok(json!({})))).await.unwrap();
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn fetch_shares_but_does_not_cache_errors() {
+		let cache = ttl_cache(60_000);
+		let calls = AtomicUsize::new(0);
+		let failure = || fetch(&calls, Err(RpcError::Custom("upstream down".to_string())));
+		let results = join_all((0..3).map(|_| cache.fetch("status", &[], failure()))).await;
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+		assert!(results.iter().all(|r| r.is_err()));
+		assert_eq!(cache.fetch("status", &[], fetch(&calls, Ok(json!(1)))).await.unwrap(), json!(1));
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn fetch_expires_entries_after_ttl() {
+		let cache = ttl_cache(50);
+		let calls = AtomicUsize::new(0);
+		assert_eq!(cache.fetch("status", &[], fetch(&calls, Ok(json!(1)))).await.unwrap(), json!(1));
+		assert_eq!(cache.fetch("status", &[], fetch(&calls, Ok(json!(2)))).await.unwrap(), json!(1));
+		sleep(Duration::from_millis(60)).await;
+		assert_eq!(cache.fetch("status", &[], fetch(&calls, Ok(json!(3)))).await.unwrap(), json!(3));
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn fetch_skips_methods_without_ttl() {
+		let cache = ttl_cache(60_000);
+		let calls = AtomicUsize::new(0);
+		cache.fetch("block", &[json!(1)], fetch(&calls, Ok(json!(1)))).await.unwrap();
+		cache.fetch("block", &[json!(1)], fetch(&calls, Ok(json!(1)))).await.unwrap();
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+	}
+}
@@ -1,22 +1,43 @@
-use std::{collections::{HashMap, HashSet}, net::SocketAddr, time::Duration};
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	sync::{Arc, RwLock},
+	time::Duration,
+};
 use anyhow::{anyhow, Result, Error};
-use hyper::{Body, Request, Method};
+use futures_util::{future::{self, FutureExt}, stream::{self, StreamExt}};
+use hyper::{Body, Request, Method, StatusCode};
 use jsonrpsee::{
-	core::{client::ClientT, params::ArrayParams, Error as RpcError},
-	http_client::{HttpClientBuilder, HttpClient},
-	server::{RpcModule, ServerBuilder},
+	core::{error::SubscriptionClosed, params::ArrayParams, Error as RpcError},
+	server::{RpcModule, ServerBuilder, ServerHandle},
     rpc_params,
-	types::{error::CallError, Params},
+	types::{error::{CallError, ErrorCode}, ErrorObject, Params},
+	SubscriptionSink,
 };
 use rand::{distributions::{Slice, Distribution}, Rng};
 use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
-use serde_json::Value as JsonValue;
-use tokio::signal::ctrl_c;
+use serde_json::{json, Value as JsonValue};
+use tokio::{
+	net::{TcpListener, TcpStream},
+	signal::{ctrl_c, unix::{signal, SignalKind}},
+	time::{interval, timeout, timeout_at, Instant, MissedTickBehavior},
+};
 use tower::ServiceBuilder;
 use tower_http::cors::{CorsLayer, Any};
-use crate::config::Config;
-use crate::proxy::{ProxyGetRequestParamsLayer, ProxyGetRequestCustomLayer};
+use crate::accesslog::{error_code, AccessLog, AccessLogEntry};
+use crate::auth::{AccessPolicy, ApiKey, ApiKeyConfig, ApiKeys, BlockSearchPolicy, PaginationPolicy, SubscriptionPolicy, TxSearchPolicy, INVALID_API_KEY_CODE};
+use crate::cache::{cache_key, ResponseCache, TtlCache};
+use crate::config::{CapMode, Config};
+use crate::context::{current_context, with_call_upstream, ContextLogger, RequestContext};
+use crate::drain::Drain;
+use crate::metrics::{self, BLOCKED_ROUTE_HITS, REQUESTS, REQUEST_DURATION, SUBSCRIPTIONS};
+use crate::proxy::{ProxyGetRequestParamsLayer, ProxyGetRequestCustomLayer, RefuseConnectionLayer, RequestContextLayer};
+use crate::ratelimit::{RateLimiter, RATE_LIMITED_CODE};
+use crate::tls::{TlsFiles, TlsTerminator};
+use crate::subscription::{SubscriptionHub, TopicSubscription};
+use crate::upstream::{Upstream, UpstreamClient, UpstreamOptions, UpstreamPool};
+use crate::websocket::CometWebsocketLayer;
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Comet34NodeInfo {
@@ -67,7 +88,7 @@ impl Comet34ValidatorInfo {
 	const ADDR_CHARS: [char; 16] = ['A', 'B', 'C', 'D', 'E', 'F', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
 
 	pub fn new() -> Result<Self> {
-		let mut rng = OsRng::default();
+		let mut rng = OsRng;
 		let address = Slice::new(&Self::ADDR_CHARS)?.sample_iter(&mut rng).take(40).collect::<String>();
 		let pub_key_bytes = rng.gen::<[u8; 32]>();
         Ok(Self {
@@ -102,28 +123,210 @@ impl Comet34Status {
 		status.node_info.listen_addr = "REDACTED".to_string();
 		status.node_info.other.rpc_address = "REDACTED".to_string();
 		status.node_info.version = "REDACTED".to_string();
-		validator_info.map(|v| status.validator_info = v.clone());
+		if let Some(v) = validator_info {
+			status.validator_info = v.clone();
+		}
 		status
 	}
 }
 
-pub type Comet34Params = Vec<String>;
+#[derive(Clone, Copy)]
+pub enum ParamKind {
+	Height,
+	/// 32 byte hash, hex with an optional `0x` prefix or base64.
+	Hash,
+	Hex,
+	Base64,
+	Bool,
+	Int { min: u64, max: u64 },
+	String,
+	Any,
+}
+
+impl std::fmt::Display for ParamKind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ParamKind::Height => f.write_str("non-negative integer height"),
+			ParamKind::Hash => f.write_str("32 byte hash in hex or base64"),
+			ParamKind::Hex => f.write_str("hex string"),
+			ParamKind::Base64 => f.write_str("base64 string"),
+			ParamKind::Bool => f.write_str("boolean"),
+			ParamKind::Int { min, max: u64::MAX } => write!(f, "integer of at least {}", min),
+			ParamKind::Int { min, max } => write!(f, "integer between {} and {}", min, max),
+			ParamKind::String => f.write_str("string"),
+			ParamKind::Any => f.write_str("any value"),
+		}
+	}
+}
+
+pub struct Param {
+	pub name: &'static str,
+	pub kind: ParamKind,
+	pub required: bool,
+}
+
+impl Param {
+	pub fn required(name: &'static str, kind: ParamKind) -> Self {
+		Self { name, kind, required: true }
+	}
+
+	pub fn optional(name: &'static str, kind: ParamKind) -> Self {
+		Self { name, kind, required: false }
+	}
+
+	pub fn validate(&self, value: &JsonValue) -> Result<()> {
+		if value.is_null() {
+			return match self.required {
+				true => Err(anyhow!("must provide parameter: {}", self.name)),
+				false => Ok(()),
+			};
+		}
+		let string = value.as_str().map(|s| s.trim_matches('"'));
+		let valid = match self.kind {
+			ParamKind::Height => parse_height(value).is_some(),
+			ParamKind::Hash => string.is_some_and(|s| {
+				let hex = s.strip_prefix("0x").unwrap_or(s);
+				(hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())) || rbase64::decode(s).is_ok_and(|b| b.len() == 32)
+			}),
+			ParamKind::Hex => string.is_some_and(|s| {
+				let hex = s.strip_prefix("0x").unwrap_or(s);
+				hex.len() % 2 == 0 && hex.chars().all(|c| c.is_ascii_hexdigit())
+			}),
+			ParamKind::Base64 => string.is_some_and(|s| !s.is_empty() && rbase64::decode(s).is_ok()),
+			ParamKind::Bool => value.is_boolean() || matches!(string, Some("true" | "false")),
+			ParamKind::Int { min, max } => parse_height(value).is_some_and(|v| (min..=max).contains(&v)),
+			ParamKind::String => string.is_some(),
+			ParamKind::Any => true,
+		};
+		if !valid {
+			return Err(anyhow!("invalid parameter {}: expected {}", self.name, self.kind));
+		}
+		Ok(())
+	}
+}
+
+pub type Comet34Params = Vec<Param>;
 
-pub fn make_params(params: Vec<impl Into<String>>) -> Comet34Params {
-	params.into_iter().map(Into::into).collect()
+/// Settings that are swapped as a whole when the config is reloaded.
+pub struct Comet34State {
+	pub public_policy: AccessPolicy,
+	pub tx_search_policy: TxSearchPolicy,
+	pub block_search_policy: BlockSearchPolicy,
+	pub pagination_policy: PaginationPolicy,
+	pub param_caps: HashMap<String, u64>,
+	pub param_cap_mode: CapMode,
+	pub subscription_policy: SubscriptionPolicy,
+	pub max_subscriptions: u32,
+	pub api_keys: ApiKeys,
+	pub pool: UpstreamPool,
+	pub archive: Option<UpstreamPool>,
+	pub rate_limiter: Arc<RateLimiter>,
+}
+
+impl Comet34State {
+	pub fn new(config: &Config, previous: Option<&Comet34State>) -> Result<Self> {
+		let client = UpstreamClient::new(UpstreamOptions::from(config))?;
+		Ok(Self {
+			public_policy: AccessPolicy {
+				allowed_routes: config.allowed_routes.clone(),
+				blocked_routes: config.blocked_routes.clone(),
+			},
+			tx_search_policy: config.tx_search.clone(),
+			block_search_policy: config.block_search.clone(),
+			pagination_policy: config.pagination.clone(),
+			param_caps: config.param_caps.clone(),
+			param_cap_mode: config.param_cap_mode,
+			subscription_policy: config.subscribe.clone(),
+			max_subscriptions: config.max_subscriptions,
+			api_keys: ApiKeys::new(
+				config.api_keys.iter().cloned().chain(match &config.api_keys_file {
+					Some(path) => ApiKeyConfig::from_file(path)?,
+					None => vec![],
+				}).collect(),
+				&config.rate_limit_route_costs,
+				previous.map(|p| &p.api_keys),
+			)?,
+			pool: UpstreamPool::new(&config.rpc_addrs, config.max_block_lag, &client, previous.map(|p| &p.pool))?,
+			archive: match config.archive_rpc_addrs.is_empty() {
+				true => None,
+				false => Some(UpstreamPool::new(
+					&config.archive_rpc_addrs,
+					config.max_block_lag,
+					&client,
+					previous.and_then(|p| p.archive.as_ref()),
+				)?),
+			},
+			rate_limiter: RateLimiter::new_or_reuse(
+				config.rate_limit_per_second,
+				config.rate_limit_burst,
+				config.rate_limit_route_costs.clone(),
+				previous.map(|p| &p.rate_limiter),
+			),
+		})
+	}
+
+	pub fn validate(&self, routes: &HashMap<String, Comet34Params>) -> Result<()> {
+		for route in self.rate_limiter.route_costs.keys() {
+			if !routes.contains_key(route) {
+				return Err(anyhow!("unknown route in rate limit costs: {}", route));
+			}
+		}
+		self.rate_limiter.validate()?;
+		for cap in self.param_caps.keys() {
+			let known = match cap.split_once('.') {
+				Some(("blockchain", "span")) => true,
+				Some((route, param)) => routes.get(route).is_some_and(|params| params.iter().any(|p| p.name == param)),
+				None => false,
+			};
+			if !known {
+				return Err(anyhow!("unknown route param in param caps: {}", cap));
+			}
+		}
+		self.public_policy.validate(|r| routes.contains_key(r))?;
+		for key in self.api_keys.iter() {
+			key.policy.validate(|r| routes.contains_key(r)).map_err(|e| anyhow!("API key {}: {}", key.name, e))?;
+		}
+		Ok(())
+	}
+
+	pub fn upstreams(&self) -> impl Iterator<Item = &Arc<Upstream>> {
+		self.pool.upstreams.iter().chain(self.archive.iter().flat_map(|a| &a.upstreams))
+	}
+
+	/// Resolves the caller's API key, requests without a key get the public policy.
+	pub fn authenticate(&self, context: Option<&RequestContext>) -> Result<Option<&ApiKey>, RpcError> {
+		match context.and_then(|c| c.api_key.as_ref().map(|k| (c, k))) {
+			Some((context, key)) => match self.api_keys.get(key) {
+				Some(api_key) => Ok(Some(api_key)),
+				None => {
+					context.set_http_status(StatusCode::UNAUTHORIZED);
+					Err(RpcError::Call(CallError::Custom(ErrorObject::owned(INVALID_API_KEY_CODE, "invalid API key", None::<()>))))
+				},
+			},
+			None => Ok(None),
+		}
+	}
 }
 
 pub struct Comet34Backend {
-	pub blocked_routes: HashSet<String>,
+	pub config: Config,
+	pub state: RwLock<Arc<Comet34State>>,
 	pub listen_addr: SocketAddr,
-	pub http: HttpClient,
+	pub tls: Option<TlsTerminator>,
+	pub metrics_listen_addr: Option<SocketAddr>,
+	pub access_log: Option<AccessLog>,
 	pub routes: HashMap<String, Comet34Params>,
-	pub url: String,
+	pub cache: Option<ResponseCache>,
+	pub ttl_cache: TtlCache,
+	pub health_check_interval_seconds: u32,
 	pub max_connections: u32,
     pub max_subscriptions_per_connection: u32,
     pub max_request_body_size_bytes: u32,
     pub max_response_body_size_bytes: u32,
     pub ws_ping_interval_seconds: u32,
+	pub drain_timeout_seconds: u32,
+	pub drain: Drain,
+	pub subscriptions: SubscriptionHub,
 	pub validator_info: Comet34ValidatorInfo,
 }
 
@@ -131,74 +334,100 @@ impl TryFrom<Config> for Comet34Backend {
 	type Error = Error;
 
 	fn try_from(config: Config) -> Result<Self> {
-		Self::new(
-			&config.rpc_addr,
-			&config.listen_addr,
-			&config.blocked_routes,
-			config.max_connections,
-			config.max_subscriptions_per_connection,
-			config.max_request_body_size_bytes,
-			config.max_response_body_size_bytes,
-			config.ws_ping_interval_seconds,
-			Comet34ValidatorInfo::new()?,
-		)
+		Self::new(&config, Comet34ValidatorInfo::new()?)
 	}
 }
 
 impl Comet34Backend {
-	pub fn new(
-		url: &str,
-		listen_addr: &str,
-		blocked_routes: &HashSet<String>,
-		max_connections: u32,
-    	max_subscriptions_per_connection: u32,
-    	max_request_body_size_bytes: u32,
-    	max_response_body_size_bytes: u32,
-   		ws_ping_interval_seconds: u32,
-		validator_info: Comet34ValidatorInfo,
-	) -> Result<Self> {
+	pub fn new(config: &Config, validator_info: Comet34ValidatorInfo) -> Result<Self> {
 		let mut backend = Self {
-			blocked_routes: blocked_routes.clone(),
-			listen_addr: listen_addr.parse()?,
-			http: HttpClientBuilder::default().build(&url)?,
+			config: config.clone(),
+			state: RwLock::new(Arc::new(Comet34State::new(config, None)?)),
+			listen_addr: config.listen_addr.parse()?,
+			tls: match (&config.tls_cert_file, &config.tls_key_file) {
+				(Some(cert), Some(key)) => Some(TlsTerminator::new(TlsFiles {
+					cert: cert.clone(),
+					key: key.clone(),
+					client_ca: config.tls_client_ca_file.clone(),
+				})?),
+				_ => None,
+			},
+			metrics_listen_addr: config.metrics_listen_addr.as_deref().map(str::parse).transpose()?,
+			access_log: config.access_log.as_deref().map(AccessLog::open).transpose()?,
 			routes: HashMap::new(),
-			url: url.to_string(),
-			max_connections,
-			max_subscriptions_per_connection,
-			max_request_body_size_bytes,
-			max_response_body_size_bytes,
-			ws_ping_interval_seconds,
+			cache: match config.cache_max_bytes {
+				0 => None,
+				max_bytes => Some(ResponseCache::new(max_bytes)),
+			},
+			ttl_cache: TtlCache::new(&config.cache_ttls_ms),
+			health_check_interval_seconds: config.health_check_interval_seconds,
+			max_connections: config.max_connections,
+			max_subscriptions_per_connection: config.max_subscriptions_per_connection,
+			max_request_body_size_bytes: config.max_request_body_size_bytes,
+			max_response_body_size_bytes: config.max_response_body_size_bytes,
+			ws_ping_interval_seconds: config.ws_ping_interval_seconds,
+			drain_timeout_seconds: config.drain_timeout_seconds,
+			drain: Drain::default(),
+			subscriptions: SubscriptionHub::default(),
 			validator_info,
 		};
+		let height = || Param::optional("height", ParamKind::Height);
+		let page = || Param::optional("page", ParamKind::Int { min: 1, max: u64::MAX });
+		// upper bounds of per_page and limit come from `param_caps` and the pagination policy
+		let per_page = || Param::optional("per_page", ParamKind::Int { min: 1, max: u64::MAX });
+		let tx = || vec![Param::required("tx", ParamKind::Base64)];
 		backend.add_route("abci_info", vec![]);
-		backend.add_route("abci_query", make_params(vec!["path", "data", "height", "prove"]));
-		backend.add_route("block", make_params(vec!["height"]));
-		backend.add_route("block_by_hash", make_params(vec!["hash"]));
-		backend.add_route("block_results", make_params(vec!["height"]));
-		backend.add_route("block_search", make_params(vec!["query", "page", "per_page", "order_by", "match_events"]));
-		backend.add_route("blockchain", make_params(vec!["minHeight", "maxHeight"]));
-		backend.add_route("broadcast_evidence", make_params(vec!["evidence"]));
-		backend.add_route("broadcast_tx_async", make_params(vec!["tx"]));
-		backend.add_route("broadcast_tx_commit", make_params(vec!["tx"]));
-		backend.add_route("broadcast_tx_sync", make_params(vec!["tx"]));
-		backend.add_route("check_tx", make_params(vec!["tx"]));
-		backend.add_route("commit", make_params(vec!["height"]));
-		backend.add_route("consensus_params", make_params(vec!["height"]));
+		backend.add_route("abci_query", vec![
+			Param::optional("path", ParamKind::String),
+			Param::optional("data", ParamKind::Hex),
+			height(),
+			Param::optional("prove", ParamKind::Bool),
+		]);
+		backend.add_route("block", vec![height()]);
+		backend.add_route("block_by_hash", vec![Param::required("hash", ParamKind::Hash)]);
+		backend.add_route("block_results", vec![height()]);
+		backend.add_route("block_search", vec![
+			Param::required("query", ParamKind::String),
+			page(),
+			per_page(),
+			Param::optional("order_by", ParamKind::String),
+			Param::optional("match_events", ParamKind::Bool),
+		]);
+		backend.add_route("blockchain", vec![
+			Param::optional("minHeight", ParamKind::Height),
+			Param::optional("maxHeight", ParamKind::Height),
+		]);
+		backend.add_route("broadcast_evidence", vec![Param::required("evidence", ParamKind::Any)]);
+		backend.add_route("broadcast_tx_async", tx());
+		backend.add_route("broadcast_tx_commit", tx());
+		backend.add_route("broadcast_tx_sync", tx());
+		backend.add_route("check_tx", tx());
+		backend.add_route("commit", vec![height()]);
+		backend.add_route("consensus_params", vec![height()]);
 		backend.add_route("consensus_state", vec![]);
 		backend.add_route("dump_consensus_state", vec![]);
 		backend.add_route("genesis", vec![]);
-		backend.add_route("genesis_chunked", make_params(vec!["chunk"]));
+		backend.add_route("genesis_chunked", vec![Param::optional("chunk", ParamKind::Int { min: 0, max: u64::MAX })]);
 		backend.add_route("health", vec![]);
 		backend.add_route("net_info", vec![]);
 		backend.add_route("num_unconfirmed_txs", vec![]);
 		backend.add_route("status", vec![]);
-		backend.add_route("subscribe", make_params(vec!["query"]));
-		backend.add_route("tx", make_params(vec!["hash", "prove"]));
-		backend.add_route("tx_search", make_params(vec!["query", "prove", "page", "per_page", "order_by", "match_events"]));
-		backend.add_route("unconfirmed_txs", make_params(vec!["limit"]));
+		// websocket only, params are read by `open_subscription`, jsonrpsee's unsubscribe handler and `CometSession`
+		backend.add_route("subscribe", vec![]);
+		backend.add_route("tx", vec![Param::required("hash", ParamKind::Hash), Param::optional("prove", ParamKind::Bool)]);
+		backend.add_route("tx_search", vec![
+			Param::required("query", ParamKind::String),
+			Param::optional("prove", ParamKind::Bool),
+			page(),
+			per_page(),
+			Param::optional("order_by", ParamKind::String),
+			Param::optional("match_events", ParamKind::Bool),
+		]);
+		backend.add_route("unconfirmed_txs", vec![Param::optional("limit", ParamKind::Int { min: 1, max: u64::MAX })]);
 		backend.add_route("unsubscribe_all", vec![]);
-		backend.add_route("unsubscribe", make_params(vec!["query"]));
-		backend.add_route("validators", make_params(vec!["height", "page", "per_page"]));
+		backend.add_route("unsubscribe", vec![]);
+		backend.add_route("validators", vec![height(), page(), per_page()]);
+		backend.state().validate(&backend.routes)?;
 		Ok(backend)
 	}
 
@@ -206,23 +435,61 @@ impl Comet34Backend {
 		self.routes.insert(method.into(), params);
 	}
 
+	pub fn state(&self) -> Arc<Comet34State> {
+		self.state.read().expect("state lock poisoned").clone()
+	}
+
+	/// Registers every route, access policies are checked per call so they can change on reload.
 	pub fn register_route(&'static self, module: &mut RpcModule<()>, method: &'static String) -> Result<(), RpcError> {
-		if !self.blocked_routes.contains(method) {
-			match method.as_str() {
-				"status" => { module.register_async_method(method, |_, _| self.status())?; },
-				"tx_search" => { module.register_async_method(method, |p, _| self.tx_search(p))?; },
-				_ => { module.register_async_method(method, |p, _| self.proxy_call(method, p))?; },
-			}
-			tracing::debug!("registered route: {}", method);
+		module.register_async_method(method, |p, _| self.call(method, p, current_context()))?;
+		tracing::debug!("registered route: {}", method);
+		Ok(())
+	}
+
+	/// Registers `subscribe` as a websocket subscription backed by a shared upstream subscription, clients unsubscribe
+	/// by passing the subscription ID to `unsubscribe`. Serves `/`, `/websocket` is served by `CometSession`.
+	pub fn register_subscriptions(&'static self, module: &mut RpcModule<()>) -> Result<(), RpcError> {
+		module.register_subscription("subscribe", "subscribe", "unsubscribe", |params, sink, _| {
+			tokio::spawn(self.subscribe(params.into_owned(), sink, current_context()));
+			Ok(())
+		})?;
+		tracing::debug!("registered subscription: subscribe");
+		Ok(())
+	}
+
+	/// Reloads the config and swaps in new route policies, rate limits, API keys and upstreams.
+	/// Settings that are only read on startup are left unchanged.
+	pub fn reload(&'static self) -> Result<()> {
+		let config = Config::load()?;
+		let current = self.state();
+		let state = Arc::new(Comet34State::new(&config, Some(&current))?);
+		state.validate(&self.routes)?;
+		*self.state.write().expect("state lock poisoned") = state.clone();
+		for upstream in current.upstreams().filter(|u| !state.upstreams().any(|n| Arc::ptr_eq(u, n))) {
+			tracing::info!("removed upstream: {}", upstream.url);
+			upstream.retire();
+		}
+		for upstream in state.upstreams().filter(|n| !current.upstreams().any(|u| Arc::ptr_eq(u, n))) {
+			tracing::info!("added upstream: {}", upstream.url);
+			tokio::spawn(self.probe_upstream(upstream.clone()));
+		}
+		for setting in self.config.restart_required_changes(&config) {
+			tracing::warn!("ignoring change to {}, it requires a restart", setting);
 		}
 		Ok(())
 	}
 
 	pub async fn start(&'static self) -> Result<()> {
+		let readyz_proxy_call = Box::leak(Box::new(|_: &Request<Body>| self.readiness()));
 		let service_builder = ServiceBuilder::default()
+			.layer(ProxyGetRequestCustomLayer::new("/healthz", &healthz_proxy_call)?)
+			.layer(ProxyGetRequestCustomLayer::new("/readyz", readyz_proxy_call)?)
+			.layer(RequestContextLayer::new(&self.drain))
 			.layer(ProxyGetRequestParamsLayer::new())
 			.layer(ProxyGetRequestCustomLayer::new("/", &root_html_proxy_call)?)
-			.layer(CorsLayer::new().allow_methods(vec![Method::GET, Method::POST]).allow_origin(Any).allow_headers(Any));
+			.layer(CorsLayer::new().allow_methods(vec![Method::GET, Method::POST]).allow_origin(Any).allow_headers(Any))
+			.layer(CometWebsocketLayer::new(self))
+			.layer(RefuseConnectionLayer::new());
 		let server = ServerBuilder::default()
 			.max_connections(self.max_connections)
 			.max_subscriptions_per_connection(self.max_subscriptions_per_connection)
@@ -230,119 +497,601 @@ impl Comet34Backend {
 			.max_response_body_size(self.max_response_body_size_bytes)
 			.ping_interval(Duration::from_secs(self.ws_ping_interval_seconds.into()))
 			.set_middleware(service_builder)
-			.build(self.listen_addr).await?;
+			.set_logger(ContextLogger::default())
+			.build(match self.tls {
+				Some(_) => SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+				None => self.listen_addr,
+			}).await?;
 		let mut module = RpcModule::new(());
 		self.routes
-			.iter()
-			.map(|(method, _)| self.register_route(&mut module, method))
+			.keys()
+			.filter(|method| !SUBSCRIPTION_ROUTES.contains(&method.as_str()))
+			.map(|method| self.register_route(&mut module, method))
 			.collect::<Result<Vec<_>, RpcError>>()?;
+		self.register_subscriptions(&mut module)?;
+		for upstream in self.state().upstreams() {
+			tokio::spawn(self.probe_upstream(upstream.clone()));
+		}
+		metrics::init(self.routes.keys().map(String::as_str));
+		if let Some(addr) = self.metrics_listen_addr {
+			let metrics_server = metrics::server(addr)?;
+			tokio::spawn(async move {
+				if let Err(e) = metrics_server.await {
+					tracing::error!("metrics server failed: {}", e);
+				}
+			});
+			tracing::info!("metrics server started on {}", addr);
+		}
+		let local_addr = server.local_addr()?;
+		if let Some(tls) = &self.tls {
+			let listener = TcpListener::bind(self.listen_addr).await?;
+			tokio::spawn(tls.serve(listener, local_addr));
+			tokio::spawn(tls.watch());
+			tracing::info!("terminating TLS on {}{}", self.listen_addr, if tls.files.client_ca.is_some() { " with client certificates" } else { "" });
+		}
 		let handle = server.start(module)?;
 		tracing::info!("server started");
-		ctrl_c().await?;
-		tracing::info!("received SIGINT, shutting down...");
-		handle.stop().map_err(Error::from)
+		let mut hangup = signal(SignalKind::hangup())?;
+		let mut terminate = signal(SignalKind::terminate())?;
+		let received = loop {
+			tokio::select! {
+				res = ctrl_c() => {
+					res?;
+					break "SIGINT";
+				},
+				_ = terminate.recv() => break "SIGTERM",
+				_ = hangup.recv() => {
+					tracing::info!("received SIGHUP, reloading config...");
+					match self.reload() {
+						Ok(()) => tracing::info!("config reloaded"),
+						Err(e) => tracing::error!("config reload failed, keeping current config: {}", e),
+					}
+				},
+			}
+		};
+		tracing::info!("received {}, draining connections for up to {}s...", received, self.drain_timeout_seconds);
+		self.drain(handle, local_addr).await
 	}
 
-	pub async fn status(&'static self) -> Result<JsonValue, RpcError> {
-		let res = self.http.request("status", rpc_params![]).await?;
-		let status: Comet34Status = serde_json::from_value(res)?;
-		serde_json::to_value(status.strip_sensitive_info(Some(&self.validator_info))).map_err(RpcError::from)
+	/// Ready when not draining and at least one upstream has been probed and is healthy, caught up and within `max_block_lag`.
+	pub fn readiness(&self) -> (StatusCode, String) {
+		if self.drain.is_draining() {
+			return (StatusCode::SERVICE_UNAVAILABLE, "draining".to_string());
+		}
+		let state = self.state();
+		let best = state.pool.best_block_height();
+		match state.pool.upstreams.iter().any(|u| u.is_probed() && state.pool.is_in_sync(u, best)) {
+			true => (StatusCode::OK, "ready".to_string()),
+			false => (StatusCode::SERVICE_UNAVAILABLE, "no upstream in sync".to_string()),
+		}
 	}
 
-	pub async fn tx_search(&'static self, params: Params<'static>) -> Result<JsonValue, RpcError> {
-		let params_json: JsonValue = params.parse()?;
-		let query = match params_json {
-			JsonValue::Object(o) => {
-				if o.contains_key("prove") || o.contains_key("per_page") || o.contains_key("order_by") || o.contains_key("match_events") {
-					return Err(RpcError::Call(CallError::InvalidParams(anyhow!("unsupported parameter(s)"))));
-				}
-				o.get("page").map(|p|
-					if serde_json::to_string(p)? != "\"1\"" { Err(RpcError::Call(CallError::InvalidParams(anyhow!("pagination not supported")))) }
-					else { Ok(()) }
-				).transpose()?;
-				o.get("query").ok_or(RpcError::Call(CallError::InvalidParams(anyhow!("must provide parameter: query"))))?.clone()
+	/// Reports not ready, refuses new requests and waits for in-flight HTTP requests before stopping the server, which
+	/// closes websockets once their pending calls are answered.
+	pub async fn drain(&'static self, handle: ServerHandle, local_addr: SocketAddr) -> Result<()> {
+		let deadline = Instant::now() + Duration::from_secs(self.drain_timeout_seconds.into());
+		self.drain.start();
+		if timeout_at(deadline, self.drain.wait_idle()).await.is_err() {
+			tracing::warn!("drain timed out with HTTP requests in flight");
+		}
+		self.drain.close_websockets();
+		handle.stop()?;
+		// jsonrpsee only checks for the stop signal when its accept loop is woken up, which a connection attempt does
+		let wake_ip = match local_addr.ip() {
+			ip if ip.is_unspecified() && ip.is_ipv4() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+			ip if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+			ip => ip,
+		};
+		let _ = timeout(Duration::from_secs(1), TcpStream::connect((wake_ip, local_addr.port()))).await;
+		match timeout_at(deadline.max(Instant::now() + Duration::from_secs(1)), handle.stopped()).await {
+			Ok(()) => tracing::info!("all connections drained, shutting down"),
+			Err(_) => tracing::warn!("drain timed out, closing remaining connections"),
+		}
+		Ok(())
+	}
+
+	pub async fn call(
+		&'static self,
+		method: &'static str,
+		params: Params<'static>,
+		context: Option<Arc<RequestContext>>,
+	) -> Result<JsonValue, RpcError> {
+		let started_at = Instant::now();
+		let (res, upstream) = with_call_upstream(self.handle_call(method, params, context.clone())).await;
+		self.record(
+			method,
+			context.as_deref(),
+			started_at.elapsed(),
+			res.as_ref().err(),
+			upstream.as_deref(),
+			match (&self.access_log, &res) {
+				(Some(_), Ok(value)) => serde_json::to_vec(value).map(|b| b.len()).unwrap_or_default(),
+				_ => 0,
 			},
-			JsonValue::Array(a) => {
-				if a.len() != 6 {
-					return Err(RpcError::Call(CallError::InvalidParams(
-						anyhow!("expected 6 parameter(s), got {}", a.len())
-					)));
-				}
-				a[2..].iter()
-					.map(|p| 
-						if p.is_null() { Ok(()) }
-						else { Err(RpcError::Call(CallError::InvalidParams(anyhow!("unsupported parameter: {}", p)))) }
-					)
-					.collect::<Result<Vec<_>, RpcError>>()?;
-				a[0].clone()
+		);
+		res
+	}
+
+	/// Updates request metrics and writes the access log entry of a call.
+	pub fn record(
+		&self,
+		method: &str,
+		context: Option<&RequestContext>,
+		latency: Duration,
+		error: Option<&RpcError>,
+		upstream: Option<&str>,
+		response_bytes: usize,
+	) {
+		REQUESTS.with_label_values(&[method, if error.is_none() { "ok" } else { "error" }]).inc();
+		REQUEST_DURATION.with_label_values(&[method]).observe(latency.as_secs_f64());
+		if let Some(access_log) = &self.access_log {
+			let state = self.state();
+			access_log.write(&AccessLogEntry {
+				timestamp_ms: AccessLogEntry::now_ms(),
+				client_ip: context.map(|c| c.client_ip),
+				api_key: context
+					.and_then(|c| c.api_key.as_deref())
+					.and_then(|k| state.api_keys.get(k))
+					.map(|k| k.name.as_str()),
+				transport: context.map(|c| c.transport),
+				method,
+				http_status: context.and_then(|c| c.http_status()).map(|s| s.as_u16()),
+				error_code: error.map(error_code),
+				latency_ms: latency.as_secs_f64() * 1000.0,
+				upstream,
+				response_bytes,
+			});
+		}
+	}
+
+	/// Checks the caller's API key, route policy and rate limit.
+	pub fn authorize<'a>(
+		&self,
+		state: &'a Comet34State,
+		method: &str,
+		context: Option<&RequestContext>,
+	) -> Result<Option<&'a ApiKey>, RpcError> {
+		let api_key = state.authenticate(context)?;
+		if !api_key.map_or(&state.public_policy, |k| &k.policy).allows(method) {
+			BLOCKED_ROUTE_HITS.with_label_values(&[method]).inc();
+			return Err(RpcError::Call(CallError::Custom(ErrorCode::MethodNotFound.into())));
+		}
+		if let Some(context) = context {
+			let allowed = match api_key {
+				Some(key) => key.rate_limiter.check((), method),
+				None => state.rate_limiter.check(context.client_ip, method),
+			};
+			if !allowed {
+				context.set_http_status(StatusCode::TOO_MANY_REQUESTS);
+				return Err(RpcError::Call(CallError::Custom(ErrorObject::owned(RATE_LIMITED_CODE, "rate limit exceeded", None::<()>))));
+			}
+		}
+		Ok(api_key)
+	}
+
+	pub async fn handle_call(
+		&'static self,
+		method: &'static str,
+		params: Params<'static>,
+		context: Option<Arc<RequestContext>>,
+	) -> Result<JsonValue, RpcError> {
+		let state = self.state();
+		let api_key = self.authorize(&state, method, context.as_deref())?;
+		match method {
+			"status" => self.status(&state).await,
+			"unsubscribe_all" => Ok(self.unsubscribe_all(context.as_deref())),
+			"tx_search" => self.tx_search(
+				&state,
+				params,
+				api_key.map_or(&state.tx_search_policy, |k| &k.tx_search),
+				api_key.map_or(&state.pagination_policy, |k| &k.pagination),
+			).await,
+			"block_search" => self.block_search(
+				&state,
+				params,
+				api_key.map_or(&state.block_search_policy, |k| &k.block_search),
+				api_key.map_or(&state.pagination_policy, |k| &k.pagination),
+			).await,
+			_ => self.proxy_call(&state, method, params).await,
+		}
+	}
+
+	/// Forwards the events of the query's shared upstream subscription to the client until the client leaves.
+	pub async fn subscribe(&'static self, params: Params<'static>, mut sink: SubscriptionSink, context: Option<Arc<RequestContext>>) {
+		let started_at = Instant::now();
+		let (res, upstream) = with_call_upstream(self.open_subscription(params, context.as_deref())).await;
+		self.record("subscribe", context.as_deref(), started_at.elapsed(), res.as_ref().err(), upstream.as_deref(), 0);
+		let events = match res {
+			Ok(events) => events,
+			Err(e) => {
+				let _ = sink.reject(e);
+				return;
 			},
-			_ => return Err(RpcError::Call(CallError::InvalidParams(
-				anyhow!("expected object or array, got {}", params_json)
-			))),
 		};
-		let query_parts = query
-			.as_str()
-			.ok_or(RpcError::Call(CallError::InvalidParams(anyhow!("query must be a string"))))?
-			.trim_matches('"')
-			.split('=')
-			.collect::<Vec<&str>>();
-		if query_parts.len() != 2 {
-			return Err(RpcError::Call(CallError::InvalidParams(anyhow!("query must be in the form key=value"))));
+		let unsubscribed = match &context {
+			Some(context) => context.unsubscribed.notified().boxed(),
+			None => future::pending().boxed(),
+		};
+		let events = stream::unfold(events, |mut events| async move { Some((events.next().await, events)) })
+			.take_until(unsubscribed)
+			.boxed();
+		SUBSCRIPTIONS.with_label_values(&["subscribe"]).inc();
+		match sink.pipe_from_try_stream(events).await {
+			SubscriptionClosed::Success => {
+				sink.close(SubscriptionClosed::Success);
+			},
+			SubscriptionClosed::Failed(e) => {
+				sink.close(e);
+			},
+			SubscriptionClosed::RemotePeerAborted => {},
 		}
-		if query_parts[0] != "tx.hash" {
-			return Err(RpcError::Call(CallError::InvalidParams(anyhow!("only tx.hash queries are supported"))));
+		SUBSCRIPTIONS.with_label_values(&["subscribe"]).dec();
+	}
+
+	pub async fn open_subscription(&'static self, params: Params<'static>, context: Option<&RequestContext>) -> Result<TopicSubscription, RpcError> {
+		let state = self.state();
+		let api_key = self.authorize(&state, "subscribe", context)?;
+		let query = api_key.map_or(&state.subscription_policy, |k| &k.subscribe)
+			.check(&parse_query(params)?)
+			.map_err(|e| RpcError::Call(CallError::InvalidParams(e)))?;
+		// resubscribes go to the current upstreams, which may have changed on reload
+		self.subscriptions.subscribe(&query.to_string(), state.max_subscriptions, move |query| async move { self.state().pool.subscribe(&query).await }).await
+	}
+
+	/// Ends every subscription of the caller's connection.
+	pub fn unsubscribe_all(&self, context: Option<&RequestContext>) -> JsonValue {
+		if let Some(context) = context {
+			context.unsubscribed.notify_waiters();
 		}
-		let tx_hash = query_parts[1].trim_matches('\'');
-		if tx_hash.len() != 64 || !tx_hash.chars().all(|c| c.is_ascii_hexdigit()) {
-			return Err(RpcError::Call(CallError::InvalidParams(anyhow!("tx.hash must be 64 characters long and contain only hex digits"))));
+		json!({})
+	}
+
+	pub async fn probe_upstream(&'static self, upstream: Arc<Upstream>) {
+		let period = Duration::from_secs(self.health_check_interval_seconds.max(1).into());
+		let mut ticker = interval(period);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			if upstream.is_retired() {
+				break;
+			}
+			let healthy = match timeout(period, self.probe(&upstream)).await {
+				Ok(Ok(status)) => {
+					upstream.set_sync_info(
+						status.sync_info.latest_block_height.parse().unwrap_or_default(),
+						status.sync_info.earliest_block_height.parse().unwrap_or_default(),
+						status.sync_info.catching_up,
+					);
+					true
+				},
+				Ok(Err(e)) => {
+					tracing::debug!("upstream {} probe failed: {}", upstream.url, e);
+					false
+				},
+				Err(_) => {
+					tracing::debug!("upstream {} probe timed out", upstream.url);
+					false
+				},
+			};
+			upstream.set_healthy(healthy);
 		}
+	}
+
+	pub async fn probe(&self, upstream: &Upstream) -> Result<Comet34Status, RpcError> {
+		upstream.request("health", rpc_params![]).await?;
+		let res = upstream.request("status", rpc_params![]).await?;
+		serde_json::from_value(res).map_err(RpcError::from)
+	}
+
+	pub async fn status(&'static self, state: &Comet34State) -> Result<JsonValue, RpcError> {
+		let res = self.ttl_cache.fetch("status", &[], state.pool.request("status", rpc_params![])).await?;
+		let status: Comet34Status = serde_json::from_value(res)?;
+		serde_json::to_value(status.strip_sensitive_info(Some(&self.validator_info))).map_err(RpcError::from)
+	}
+
+	pub async fn tx_search(
+		&'static self,
+		state: &Comet34State,
+		params: Params<'static>,
+		policy: &TxSearchPolicy,
+		pagination: &PaginationPolicy,
+	) -> Result<JsonValue, RpcError> {
+		let mut values = self.route_params(state, "tx_search", params)?;
+		if !policy.allow_prove && !values[1].is_null() {
+			return Err(RpcError::Call(CallError::InvalidParams(anyhow!("unsupported parameter: prove"))));
+		}
+		pagination.check(&values[2], &values[3], &values[4]).map_err(|e| RpcError::Call(CallError::InvalidParams(e)))?;
+		let query = values[0]
+			.as_str()
+			.ok_or(RpcError::Call(CallError::InvalidParams(anyhow!("query must be a string"))))?;
+		let query = policy.check(query).map_err(|e| RpcError::Call(CallError::InvalidParams(e)))?;
+		// forward the query as validated, not the client's spelling of it
+		values[0] = JsonValue::String(query.to_string());
+		let mut params = ArrayParams::new();
+		values.iter().map(|p| params.insert(p)).collect::<Result<Vec<()>, serde_json::Error>>()?;
+		state.pool.request("tx_search", params).await
+	}
+
+	pub async fn block_search(
+		&'static self,
+		state: &Comet34State,
+		params: Params<'static>,
+		policy: &BlockSearchPolicy,
+		pagination: &PaginationPolicy,
+	) -> Result<JsonValue, RpcError> {
+		let mut values = self.route_params(state, "block_search", params)?;
+		pagination.check(&values[1], &values[2], &values[3]).map_err(|e| RpcError::Call(CallError::InvalidParams(e)))?;
+		let query = values[0]
+			.as_str()
+			.ok_or(RpcError::Call(CallError::InvalidParams(anyhow!("query must be a string"))))?;
+		let query = policy.check(query).map_err(|e| RpcError::Call(CallError::InvalidParams(e)))?;
+		values[0] = JsonValue::String(query.to_string());
 		let mut params = ArrayParams::new();
-		params.insert(&query)?;
-		params.insert(&JsonValue::Null)?;
-		params.insert(&JsonValue::Null)?;
-		params.insert(&JsonValue::Null)?;
-		params.insert(&JsonValue::Null)?;
-		params.insert(&JsonValue::Null)?;
-		self.http.request("tx_search", params).await
+		values.iter().map(|p| params.insert(p)).collect::<Result<Vec<()>, serde_json::Error>>()?;
+		state.pool.request("block_search", params).await
 	}
 
-	pub async fn proxy_call(&'static self, method: &str, params: Params<'static>) -> Result<JsonValue, RpcError> {
+	/// Reads the route's params in order, by name or position, validates each against the route's schema and applies
+	/// the configured caps.
+	pub fn route_params(&self, state: &Comet34State, method: &str, params: Params) -> Result<Vec<JsonValue>, RpcError> {
 		let params_json: JsonValue = params.parse()?;
 		let method_params = self.routes.get(method)
 			.ok_or(RpcError::MethodNotFound(method.to_string()))?;
-		let params = match params_json {
-			JsonValue::Object(o) => {
-				let mut params = ArrayParams::new();
-				method_params.iter()
-					.map(|p| params.insert(o.get(p)
-					.unwrap_or(&JsonValue::Null)))
-					.collect::<Result<Vec<()>, serde_json::Error>>()?;
-				params
-			},
+		let mut values = match params_json {
+			JsonValue::Object(o) => method_params.iter()
+				.map(|p| o.get(p.name).cloned().unwrap_or(JsonValue::Null))
+				.collect::<Vec<JsonValue>>(),
 			JsonValue::Array(a) => {
 				if a.len() != method_params.len() {
+					let names = method_params.iter().map(|p| p.name).collect::<Vec<_>>();
 					return Err(RpcError::Call(CallError::InvalidParams(
-						anyhow!("expected {} parameter(s) [{}], got {}", method_params.len(), method_params.join(", "), a.len())
+						anyhow!("expected {} parameter(s) [{}], got {}", method_params.len(), names.join(", "), a.len())
 					)));
 				}
-				let mut params = ArrayParams::new();
-				a.iter().map(|p| params.insert(p)).collect::<Result<Vec<()>, serde_json::Error>>()?;
-				params
+				a
 			},
-			_ => ArrayParams::new(),
+			_ => vec![JsonValue::Null; method_params.len()],
 		};
-		self.http.request(method, params).await
+		for (param, value) in method_params.iter().zip(&values) {
+			param.validate(value).map_err(|e| RpcError::Call(CallError::InvalidParams(e)))?;
+		}
+		for (i, param) in method_params.iter().enumerate() {
+			let (Some(cap), Some(value)) = (state.param_caps.get(&format!("{}.{}", method, param.name)), parse_height(&values[i])) else {
+				continue;
+			};
+			if value > *cap {
+				match state.param_cap_mode {
+					CapMode::Clamp => values[i] = with_height(&values[i], *cap),
+					CapMode::Reject => return Err(RpcError::Call(CallError::InvalidParams(anyhow!("{} must be at most {}", param.name, cap)))),
+				}
+			}
+		}
+		if let Some(cap) = state.param_caps.get("blockchain.span").filter(|_| method == "blockchain") {
+			if let (Some(min), Some(max)) = (parse_height(&values[0]), parse_height(&values[1])) {
+				if max.saturating_sub(min) > *cap {
+					match state.param_cap_mode {
+						CapMode::Clamp => values[1] = with_height(&values[1], min.saturating_add(*cap)),
+						CapMode::Reject => return Err(RpcError::Call(CallError::InvalidParams(
+							anyhow!("maxHeight - minHeight must be at most {}", cap)
+						))),
+					}
+				}
+			}
+		}
+		Ok(values)
+	}
+
+	pub async fn proxy_call(&'static self, state: &Comet34State, method: &str, params: Params<'static>) -> Result<JsonValue, RpcError> {
+		let values = self.route_params(state, method, params)?;
+		let height = match HISTORICAL_ROUTES.contains(&method) {
+			true => self.routes[method].iter()
+				.position(|p| p.name == "height")
+				.and_then(|i| parse_height(&values[i])),
+			false => None,
+		};
+		// only heights below the tip are final, height-less queries always mean latest
+		let cache = match (&self.cache, height) {
+			(Some(cache), Some(h)) if IMMUTABLE_ROUTES.contains(&method) && h < state.pool.max_known_block_height() => {
+				let key = cache_key(method, &values);
+				if let Some(res) = cache.get(&key) {
+					return Ok(res);
+				}
+				Some((cache, key))
+			},
+			_ => None,
+		};
+		let mut params = ArrayParams::new();
+		values.iter().map(|p| params.insert(p)).collect::<Result<Vec<()>, serde_json::Error>>()?;
+		let res = self.ttl_cache.fetch(method, &values, self.forward(state, method, params, height)).await;
+		if let (Some((cache, key)), Ok(value)) = (cache, &res) {
+			cache.insert(key, value);
+		}
+		res
+	}
+
+	pub async fn forward(
+		&'static self,
+		state: &Comet34State,
+		method: &str,
+		params: ArrayParams,
+		height: Option<u64>,
+	) -> Result<JsonValue, RpcError> {
+		match &state.archive {
+			// tx is looked up by hash so its height is unknown, retry on the archive when the pruned pool can't find it
+			Some(archive) if method == "tx" => match state.pool.request(method, params.clone()).await {
+				Err(RpcError::Call(_)) => archive.request(method, params).await,
+				res => res,
+			},
+			Some(archive) if height.is_some_and(|h| !state.pool.has_height(h)) => archive.request(method, params).await,
+			_ => state.pool.request_at_height(method, params, height).await,
+		}
+	}
+}
+
+pub const HISTORICAL_ROUTES: [&str; 5] = ["block", "block_results", "commit", "validators", "consensus_params"];
+
+pub const IMMUTABLE_ROUTES: [&str; 4] = ["block", "block_results", "commit", "validators"];
+
+/// Routes served as websocket subscriptions rather than proxied calls.
+pub const SUBSCRIPTION_ROUTES: [&str; 2] = ["subscribe", "unsubscribe"];
+
+pub fn parse_height(value: &JsonValue) -> Option<u64> {
+	match value {
+		JsonValue::Number(n) => n.as_u64(),
+		JsonValue::String(s) => s.trim_matches('"').parse().ok(),
+		_ => None,
 	}
 }
 
-pub fn root_html_proxy_call(req: &Request<Body>) -> String {
+/// Replaces an integer param, keeping it a string if the client sent a string.
+fn with_height(original: &JsonValue, value: u64) -> JsonValue {
+	match original {
+		JsonValue::String(_) => JsonValue::String(value.to_string()),
+		_ => JsonValue::from(value),
+	}
+}
+
+/// Reads the `query` param, given by name or as the only positional param.
+pub fn parse_query(params: Params) -> Result<String, RpcError> {
+	let query = match params.parse::<JsonValue>()? {
+		JsonValue::Object(mut o) => o.remove("query"),
+		JsonValue::Array(a) if a.len() == 1 => a.into_iter().next(),
+		_ => None,
+	};
+	match query {
+		Some(JsonValue::String(q)) => Ok(q.trim_matches('"').to_string()),
+		Some(_) => Err(RpcError::Call(CallError::InvalidParams(anyhow!("query must be a string")))),
+		None => Err(RpcError::Call(CallError::InvalidParams(anyhow!("must provide parameter: query")))),
+	}
+}
+
+pub fn root_html_proxy_call(req: &Request<Body>) -> (StatusCode, String) {
 	let host: &str = req.headers().get("Host").map(|v| v.to_str().unwrap_or_default()).unwrap_or_default();
-	root_html(&format!("//{}", host))
+	(StatusCode::OK, root_html(&format!("//{}", host)))
+}
+
+pub fn healthz_proxy_call(_req: &Request<Body>) -> (StatusCode, String) {
+	(StatusCode::OK, "ok".to_string())
 }
 
 pub fn root_html(base: &str) -> String {
 	format!(
-		r#"<html><body><br>Available endpoints:<br><br>Endpoints that require arguments:<br><a href="{base_url}/abci_info?">{base_url}/abci_info?</a></br><a href="{base_url}/abci_query?path=_&data=_&height=_&prove=_">{base_url}/abci_query?path=_&data=_&height=_&prove=_</a></br><a href="{base_url}/block?height=_">{base_url}/block?height=_</a></br><a href="{base_url}/block_by_hash?hash=_">{base_url}/block_by_hash?hash=_</a></br><a href="{base_url}/block_results?height=_">{base_url}/block_results?height=_</a></br><a href="{base_url}/block_search?query=_&page=_&per_page=_&order_by=_&match_events=_">{base_url}/block_search?query=_&page=_&per_page=_&order_by=_&match_events=_</a></br><a href="{base_url}/blockchain?minHeight=_&maxHeight=_">{base_url}/blockchain?minHeight=_&maxHeight=_</a></br><a href="{base_url}/broadcast_evidence?evidence=_">{base_url}/broadcast_evidence?evidence=_</a></br><a href="{base_url}/broadcast_tx_async?tx=_">{base_url}/broadcast_tx_async?tx=_</a></br><a href="{base_url}/broadcast_tx_commit?tx=_">{base_url}/broadcast_tx_commit?tx=_</a></br><a href="{base_url}/broadcast_tx_sync?tx=_">{base_url}/broadcast_tx_sync?tx=_</a></br><a href="{base_url}/check_tx?tx=_">{base_url}/check_tx?tx=_</a></br><a href="{base_url}/commit?height=_">{base_url}/commit?height=_</a></br><a href="{base_url}/consensus_params?height=_">{base_url}/consensus_params?height=_</a></br><a href="{base_url}/consensus_state?">{base_url}/consensus_state?</a></br><a href="{base_url}/dump_consensus_state?">{base_url}/dump_consensus_state?</a></br><a href="{base_url}/genesis?">{base_url}/genesis?</a></br><a href="{base_url}/genesis_chunked?chunk=_">{base_url}/genesis_chunked?chunk=_</a></br><a href="{base_url}/health?">{base_url}/health?</a></br><a href="{base_url}/net_info?">{base_url}/net_info?</a></br><a href="{base_url}/num_unconfirmed_txs?">{base_url}/num_unconfirmed_txs?</a></br><a href="{base_url}/status?">{base_url}/status?</a></br><a href="{base_url}/subscribe?query=_">{base_url}/subscribe?query=_</a></br><a href="{base_url}/tx?hash=_&prove=_">{base_url}/tx?hash=_&prove=_</a></br><a href="{base_url}/tx_search?query=_&prove=_&page=_&per_page=_&order_by=_&match_events=_">{base_url}/tx_search?query=_&prove=_&page=_&per_page=_&order_by=_&match_events=_</a></br><a href="{base_url}/unconfirmed_txs?limit=_">{base_url}/unconfirmed_txs?limit=_</a></br><a href="{base_url}/unsubscribe?query=_">{base_url}/unsubscribe?query=_</a></br><a href="{base_url}/unsubscribe_all?">{base_url}/unsubscribe_all?</a></br><a href="{base_url}/validators?height=_&page=_&per_page=_">{base_url}/validators?height=_&page=_&per_page=_</a></br></body></html>"#,
+		r#"<html><body><br>Available endpoints:<br><br>Endpoints that require arguments:<br><a href="{base_url}/abci_info?">{base_url}/abci_info?</a></br><a href="{base_url}/abci_query?path=_&data=_&height=_&prove=_">{base_url}/abci_query?path=_&data=_&height=_&prove=_</a></br><a href="{base_url}/block?height=_">{base_url}/block?height=_</a></br><a href="{base_url}/block_by_hash?hash=_">{base_url}/block_by_hash?hash=_</a></br><a href="{base_url}/block_results?height=_">{base_url}/block_results?height=_</a></br><a href="{base_url}/block_search?query=_&page=_&per_page=_&order_by=_&match_events=_">{base_url}/block_search?query=_&page=_&per_page=_&order_by=_&match_events=_</a></br><a href="{base_url}/blockchain?minHeight=_&maxHeight=_">{base_url}/blockchain?minHeight=_&maxHeight=_</a></br><a href="{base_url}/broadcast_evidence?evidence=_">{base_url}/broadcast_evidence?evidence=_</a></br><a href="{base_url}/broadcast_tx_async?tx=_">{base_url}/broadcast_tx_async?tx=_</a></br><a href="{base_url}/broadcast_tx_commit?tx=_">{base_url}/broadcast_tx_commit?tx=_</a></br><a href="{base_url}/broadcast_tx_sync?tx=_">{base_url}/broadcast_tx_sync?tx=_</a></br><a href="{base_url}/check_tx?tx=_">{base_url}/check_tx?tx=_</a></br><a href="{base_url}/commit?height=_">{base_url}/commit?height=_</a></br><a href="{base_url}/consensus_params?height=_">{base_url}/consensus_params?height=_</a></br><a href="{base_url}/consensus_state?">{base_url}/consensus_state?</a></br><a href="{base_url}/dump_consensus_state?">{base_url}/dump_consensus_state?</a></br><a href="{base_url}/genesis?">{base_url}/genesis?</a></br><a href="{base_url}/genesis_chunked?chunk=_">{base_url}/genesis_chunked?chunk=_</a></br><a href="{base_url}/health?">{base_url}/health?</a></br><a href="{base_url}/net_info?">{base_url}/net_info?</a></br><a href="{base_url}/num_unconfirmed_txs?">{base_url}/num_unconfirmed_txs?</a></br><a href="{base_url}/status?">{base_url}/status?</a></br><a href="{base_url}/tx?hash=_&prove=_">{base_url}/tx?hash=_&prove=_</a></br><a href="{base_url}/tx_search?query=_&prove=_&page=_&per_page=_&order_by=_&match_events=_">{base_url}/tx_search?query=_&prove=_&page=_&per_page=_&order_by=_&match_events=_</a></br><a href="{base_url}/unconfirmed_txs?limit=_">{base_url}/unconfirmed_txs?limit=_</a></br><a href="{base_url}/unsubscribe_all?">{base_url}/unsubscribe_all?</a></br><a href="{base_url}/validators?height=_&page=_&per_page=_">{base_url}/validators?height=_&page=_&per_page=_</a></br></body></html>"#,
 		base_url = base,
 	)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn backend(config: Config) -> Comet34Backend {
+		let config = Config { rpc_addrs: vec!["http://127.0.0.1:26657".to_string()], ..config };
+		Comet34Backend::new(&config, Comet34ValidatorInfo::new().unwrap()).unwrap()
+	}
+
+	fn route_params(backend: &Comet34Backend, method: &str, params: &str) -> Result<Vec<JsonValue>, RpcError> {
+		backend.route_params(&backend.state(), method, Params::new(Some(params)))
+	}
+
+	#[test]
+	fn validate_heights() {
+		let height = Param::optional("height", ParamKind::Height);
+		assert!(height.validate(&json!(5)).is_ok());
+		assert!(height.validate(&json!("5")).is_ok());
+		assert!(height.validate(&json!(null)).is_ok());
+		assert!(height.validate(&json!(-5)).is_err());
+		assert!(height.validate(&json!("-5")).is_err());
+		assert!(height.validate(&json!("five")).is_err());
+		assert!(height.validate(&json!(1.5)).is_err());
+	}
+
+	#[test]
+	fn validate_hashes() {
+		let hash = Param::required("hash", ParamKind::Hash);
+		let hex = "ab".repeat(32);
+		assert!(hash.validate(&json!(hex)).is_ok());
+		assert!(hash.validate(&json!(hex.to_uppercase())).is_ok());
+		assert!(hash.validate(&json!(format!("0x{}", hex))).is_ok());
+		assert!(hash.validate(&json!(rbase64::encode(&[7u8; 32]))).is_ok());
+		assert!(hash.validate(&json!("xyz")).is_err());
+		assert!(hash.validate(&json!(&hex[2..])).is_err());
+		assert!(hash.validate(&json!(rbase64::encode(&[7u8; 31]))).is_err());
+		assert!(hash.validate(&json!(5)).is_err());
+	}
+
+	#[test]
+	fn validate_bounded_ints() {
+		let limit = Param::optional("limit", ParamKind::Int { min: 1, max: 100 });
+		assert!(limit.validate(&json!(1)).is_ok());
+		assert!(limit.validate(&json!("100")).is_ok());
+		assert!(limit.validate(&json!(0)).is_err());
+		assert!(limit.validate(&json!(101)).is_err());
+		assert!(limit.validate(&json!(999999)).is_err());
+	}
+
+	#[test]
+	fn validate_required_params() {
+		let query = Param::required("query", ParamKind::String);
+		assert!(query.validate(&json!(null)).is_err());
+		assert!(query.validate(&json!("tx.height = 5")).is_ok());
+		assert!(query.validate(&json!(5)).is_err());
+	}
+
+	#[test]
+	fn route_params_by_name() {
+		let backend = backend(Config::default());
+		assert_eq!(route_params(&backend, "block", r#"{"height":"5"}"#).unwrap(), vec![json!("5")]);
+		assert_eq!(route_params(&backend, "block", "{}").unwrap(), vec![JsonValue::Null]);
+		assert!(route_params(&backend, "block", r#"{"height":"-5"}"#).is_err());
+		assert!(route_params(&backend, "block_by_hash", r#"{"hash":"xyz"}"#).is_err());
+		assert!(route_params(&backend, "block_by_hash", "{}").is_err());
+		assert!(route_params(&backend, "unconfirmed_txs", r#"{"limit":999999}"#).is_err());
+	}
+
+	#[test]
+	fn route_params_by_position() {
+		let backend = backend(Config::default());
+		let hash = "ab".repeat(32);
+		assert_eq!(
+			route_params(&backend, "tx", &format!(r#"["{}", true]"#, hash)).unwrap(),
+			vec![json!(hash), json!(true)],
+		);
+		assert!(route_params(&backend, "tx", &format!(r#"["{}"]"#, hash)).is_err());
+		assert!(route_params(&backend, "tx", &format!(r#"["{}", true, 1]"#, hash)).is_err());
+		assert!(route_params(&backend, "tx", "[null, true]").is_err());
+		assert!(route_params(&backend, "health", "[]").is_ok());
+		assert!(route_params(&backend, "health", "[1]").is_err());
+	}
+
+	#[test]
+	fn route_params_clamp_keeps_representation() {
+		let backend = backend(Config { param_cap_mode: CapMode::Clamp, ..Config::default() });
+		assert_eq!(route_params(&backend, "unconfirmed_txs", r#"{"limit":50}"#).unwrap(), vec![json!(30)]);
+		assert_eq!(route_params(&backend, "unconfirmed_txs", r#"{"limit":"50"}"#).unwrap(), vec![json!("30")]);
+		assert_eq!(route_params(&backend, "unconfirmed_txs", r#"{"limit":"20"}"#).unwrap(), vec![json!("20")]);
+		assert_eq!(route_params(&backend, "unconfirmed_txs", r#"{"limit":500}"#).unwrap(), vec![json!(30)]);
+		assert_eq!(
+			route_params(&backend, "validators", r#"{"per_page":"200"}"#).unwrap(),
+			vec![JsonValue::Null, JsonValue::Null, json!("100")],
+		);
+		assert_eq!(
+			route_params(&backend, "blockchain", r#"{"minHeight":"10","maxHeight":"100"}"#).unwrap(),
+			vec![json!("10"), json!("30")],
+		);
+		assert_eq!(route_params(&backend, "blockchain", "[10, 100]").unwrap(), vec![json!(10), json!(30)]);
+		assert_eq!(route_params(&backend, "blockchain", "[10, 25]").unwrap(), vec![json!(10), json!(25)]);
+	}
+
+	#[test]
+	fn route_params_reject_above_caps() {
+		let backend = backend(Config { param_cap_mode: CapMode::Reject, ..Config::default() });
+		assert!(route_params(&backend, "unconfirmed_txs", r#"{"limit":50}"#).is_err());
+		assert!(route_params(&backend, "unconfirmed_txs", r#"{"limit":"50"}"#).is_err());
+		assert_eq!(route_params(&backend, "unconfirmed_txs", r#"{"limit":"30"}"#).unwrap(), vec![json!("30")]);
+		assert!(route_params(&backend, "validators", r#"{"per_page":"200"}"#).is_err());
+		assert!(route_params(&backend, "blockchain", r#"{"minHeight":"10","maxHeight":"100"}"#).is_err());
+		assert!(route_params(&backend, "blockchain", "[10, 30]").is_ok());
+	}
+}
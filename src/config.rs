@@ -1,91 +1,411 @@
-use std::{collections::HashSet, env};
+use std::{collections::{HashMap, HashSet}, env, fmt::Display, fs, path::Path, str::FromStr};
 use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use strum_macros::EnumString;
+use crate::auth::{ApiKeyConfig, BlockSearchPolicy, PaginationPolicy, SubscriptionPolicy, TxSearchPolicy};
 
-#[derive(Clone, EnumString, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, EnumString, Deserialize, Serialize)]
 pub enum Backend {
     Comet34
 }
 
+/// What happens to params above their cap in `param_caps`.
+#[derive(Clone, Copy, PartialEq, EnumString, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum CapMode {
+    Clamp,
+    Reject,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub backend: Backend,
+    pub allowed_routes: Option<HashSet<String>>,
     pub blocked_routes: HashSet<String>,
     pub listen_addr: String,
-    pub rpc_addr: String,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_client_ca_file: Option<String>,
+    pub metrics_listen_addr: Option<String>,
+    pub access_log: Option<String>,
+    pub rpc_addrs: Vec<String>,
+    pub archive_rpc_addrs: Vec<String>,
+    pub upstream_ca_file: Option<String>,
+    pub upstream_client_cert_file: Option<String>,
+    pub upstream_client_key_file: Option<String>,
+    pub upstream_headers: HashMap<String, String>,
+    pub upstream_request_timeout_ms: u64,
+    pub upstream_connect_timeout_ms: u64,
+    pub health_check_interval_seconds: u32,
+    pub max_block_lag: u64,
+    pub cache_max_bytes: usize,
+    pub cache_ttls_ms: HashMap<String, u64>,
+    pub rate_limit_per_second: u32,
+    pub rate_limit_burst: u32,
+    pub rate_limit_route_costs: HashMap<String, u32>,
+    pub param_caps: HashMap<String, u64>,
+    pub param_cap_mode: CapMode,
+    pub api_keys_file: Option<String>,
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub tx_search: TxSearchPolicy,
+    pub block_search: BlockSearchPolicy,
+    pub pagination: PaginationPolicy,
+    pub subscribe: SubscriptionPolicy,
+    pub max_subscriptions: u32,
     pub max_connections: u32,
     pub max_subscriptions_per_connection: u32,
     pub max_request_body_size_bytes: u32,
     pub max_response_body_size_bytes: u32,
     pub ws_ping_interval_seconds: u32,
+    pub drain_timeout_seconds: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            backend: DEFAULT_BACKEND,
+            allowed_routes: None,
+            blocked_routes: HashSet::new(),
+            listen_addr: DEFAULT_LISTEN_ADDR.to_string(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_client_ca_file: None,
+            metrics_listen_addr: None,
+            access_log: None,
+            rpc_addrs: vec![],
+            archive_rpc_addrs: vec![],
+            upstream_ca_file: None,
+            upstream_client_cert_file: None,
+            upstream_client_key_file: None,
+            upstream_headers: HashMap::new(),
+            upstream_request_timeout_ms: DEFAULT_UPSTREAM_REQUEST_TIMEOUT_MS,
+            upstream_connect_timeout_ms: DEFAULT_UPSTREAM_CONNECT_TIMEOUT_MS,
+            health_check_interval_seconds: DEFAULT_HEALTH_CHECK_INTERVAL_SECONDS,
+            max_block_lag: DEFAULT_MAX_BLOCK_LAG,
+            cache_max_bytes: DEFAULT_CACHE_MAX_BYTES,
+            cache_ttls_ms: parse_map(DEFAULT_CACHE_TTLS_MS).expect("invalid default cache TTLs"),
+            rate_limit_per_second: DEFAULT_RATE_LIMIT_PER_SECOND,
+            rate_limit_burst: DEFAULT_RATE_LIMIT_BURST,
+            rate_limit_route_costs: parse_map(DEFAULT_RATE_LIMIT_ROUTE_COSTS).expect("invalid default route costs"),
+            param_caps: parse_map(DEFAULT_PARAM_CAPS).expect("invalid default param caps"),
+            param_cap_mode: DEFAULT_PARAM_CAP_MODE,
+            api_keys_file: None,
+            api_keys: vec![],
+            tx_search: TxSearchPolicy::default(),
+            block_search: BlockSearchPolicy::default(),
+            pagination: PaginationPolicy::default(),
+            subscribe: SubscriptionPolicy::default(),
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_subscriptions_per_connection: DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION,
+            max_request_body_size_bytes: DEFAULT_MAX_REQUEST_BODY_SIZE_BYTES,
+            max_response_body_size_bytes: DEFAULT_MAX_RESPONSE_BODY_SIZE_BYTES,
+            ws_ping_interval_seconds: DEFAULT_WS_PING_INTERVAL_SECONDS,
+            drain_timeout_seconds: DEFAULT_DRAIN_TIMEOUT_SECONDS,
+        }
+    }
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self> {
-        Ok(Self {
-            backend: env::var(ENV_BACKEND)
-                .ok()
-                .map(|s| s.parse())
-                .transpose()?
-                .unwrap_or(DEFAULT_BACKEND),
-            blocked_routes: HashSet::from_iter(env::var(ENV_BLOCKED_ROUTES)
-                .unwrap_or(DEFAULT_BLOCKED_ROUTES.to_string())
-                .split(',')
-                .map(|s| s.to_string()).collect::<Vec<String>>()),
-            listen_addr: env::var(ENV_LISTEN_ADDR)
-                .unwrap_or(DEFAULT_LISTEN_ADDR.to_string()),
-            rpc_addr: env::var(ENV_RPC_ADDR)
-                .map_err(|_| anyhow!("missing required environment variable: {}", ENV_RPC_ADDR))?,
-            max_connections: env::var(ENV_MAX_CONNECTIONS)
-                .ok()
-                .map(|s| s.parse())
-                .transpose()?
-                .unwrap_or(DEFAULT_MAX_CONNECTIONS),
-            max_subscriptions_per_connection: env::var(ENV_MAX_SUBSCRIPTIONS_PER_CONNECTION)
-                .ok()
-                .map(|s| s.parse())
-                .transpose()?
-                .unwrap_or(DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION),
-            max_request_body_size_bytes: env::var(ENV_MAX_REQUEST_BODY_SIZE_BYTES)
-                .ok()
-                .map(|s| s.parse())
-                .transpose()?
-                .unwrap_or(DEFAULT_MAX_REQUEST_BODY_SIZE_BYTES),
-            max_response_body_size_bytes: env::var(ENV_MAX_RESPONSE_BODY_SIZE_BYTES)
-                .ok()
-                .map(|s| s.parse())
-                .transpose()?
-                .unwrap_or(DEFAULT_MAX_RESPONSE_BODY_SIZE_BYTES),
-            ws_ping_interval_seconds: env::var(ENV_WS_PING_INTERVAL_SECONDS)
-                .ok()
-                .map(|s| s.parse())
-                .transpose()?
-                .unwrap_or(DEFAULT_WS_PING_INTERVAL_SECONDS),
-        })
+    /// Reads the config file given by `--config` or `IGLTC_CONFIG` if there is one, then applies environment overrides.
+    pub fn load() -> Result<Self> {
+        let config = match config_path()? {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.with_env()?.validated()
+    }
+
+    /// Reads a TOML or YAML config file, settings missing from the file keep their defaults.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| anyhow!("failed reading config file {}: {}", path, e))?;
+        let res = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("toml") => serde_path_to_error::deserialize(toml::Deserializer::new(&content))
+                .map_err(|e| anyhow!("{}: {}", e.path(), e.inner().message())),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| anyhow!(e)),
+            _ => return Err(anyhow!("unsupported config file extension, expected .toml, .yaml or .yml: {}", path)),
+        };
+        res.map_err(|e| anyhow!("invalid config file {}: {}", path, e))
+    }
+
+    /// Overrides settings with the `IGLTC_*` environment variables that are set.
+    pub fn with_env(mut self) -> Result<Self> {
+        if let Some(backend) = env_var(ENV_BACKEND)? {
+            self.backend = backend;
+        }
+        if let Some(allowed_routes) = env_list(ENV_ALLOWED_ROUTES) {
+            self.allowed_routes = Some(HashSet::from_iter(allowed_routes));
+        }
+        if let Some(blocked_routes) = env_list(ENV_BLOCKED_ROUTES) {
+            self.blocked_routes = HashSet::from_iter(blocked_routes);
+        }
+        if let Some(listen_addr) = env_var(ENV_LISTEN_ADDR)? {
+            self.listen_addr = listen_addr;
+        }
+        if let Some(tls_cert_file) = env_var(ENV_TLS_CERT_FILE)? {
+            self.tls_cert_file = Some(tls_cert_file);
+        }
+        if let Some(tls_key_file) = env_var(ENV_TLS_KEY_FILE)? {
+            self.tls_key_file = Some(tls_key_file);
+        }
+        if let Some(tls_client_ca_file) = env_var(ENV_TLS_CLIENT_CA_FILE)? {
+            self.tls_client_ca_file = Some(tls_client_ca_file);
+        }
+        if let Some(metrics_listen_addr) = env_var(ENV_METRICS_LISTEN_ADDR)? {
+            self.metrics_listen_addr = Some(metrics_listen_addr);
+        }
+        if let Some(access_log) = env_var(ENV_ACCESS_LOG)? {
+            self.access_log = Some(access_log);
+        }
+        if let Some(rpc_addrs) = env_list(ENV_RPC_ADDR) {
+            self.rpc_addrs = rpc_addrs;
+        }
+        if let Some(archive_rpc_addrs) = env_list(ENV_ARCHIVE_RPC_ADDR) {
+            self.archive_rpc_addrs = archive_rpc_addrs;
+        }
+        if let Some(upstream_ca_file) = env_var(ENV_UPSTREAM_CA_FILE)? {
+            self.upstream_ca_file = Some(upstream_ca_file);
+        }
+        if let Some(upstream_client_cert_file) = env_var(ENV_UPSTREAM_CLIENT_CERT_FILE)? {
+            self.upstream_client_cert_file = Some(upstream_client_cert_file);
+        }
+        if let Some(upstream_client_key_file) = env_var(ENV_UPSTREAM_CLIENT_KEY_FILE)? {
+            self.upstream_client_key_file = Some(upstream_client_key_file);
+        }
+        if let Some(upstream_headers) = env_map(ENV_UPSTREAM_HEADERS)? {
+            self.upstream_headers = upstream_headers;
+        }
+        if let Some(upstream_request_timeout_ms) = env_var(ENV_UPSTREAM_REQUEST_TIMEOUT_MS)? {
+            self.upstream_request_timeout_ms = upstream_request_timeout_ms;
+        }
+        if let Some(upstream_connect_timeout_ms) = env_var(ENV_UPSTREAM_CONNECT_TIMEOUT_MS)? {
+            self.upstream_connect_timeout_ms = upstream_connect_timeout_ms;
+        }
+        if let Some(health_check_interval_seconds) = env_var(ENV_HEALTH_CHECK_INTERVAL_SECONDS)? {
+            self.health_check_interval_seconds = health_check_interval_seconds;
+        }
+        if let Some(max_block_lag) = env_var(ENV_MAX_BLOCK_LAG)? {
+            self.max_block_lag = max_block_lag;
+        }
+        if let Some(cache_max_bytes) = env_var(ENV_CACHE_MAX_BYTES)? {
+            self.cache_max_bytes = cache_max_bytes;
+        }
+        if let Some(cache_ttls_ms) = env_map(ENV_CACHE_TTLS_MS)? {
+            self.cache_ttls_ms = cache_ttls_ms;
+        }
+        if let Some(rate_limit_per_second) = env_var(ENV_RATE_LIMIT_PER_SECOND)? {
+            self.rate_limit_per_second = rate_limit_per_second;
+        }
+        if let Some(rate_limit_burst) = env_var(ENV_RATE_LIMIT_BURST)? {
+            self.rate_limit_burst = rate_limit_burst;
+        }
+        if let Some(rate_limit_route_costs) = env_map(ENV_RATE_LIMIT_ROUTE_COSTS)? {
+            self.rate_limit_route_costs = rate_limit_route_costs;
+        }
+        if let Some(param_caps) = env_map(ENV_PARAM_CAPS)? {
+            self.param_caps = param_caps;
+        }
+        if let Some(param_cap_mode) = env_var(ENV_PARAM_CAP_MODE)? {
+            self.param_cap_mode = param_cap_mode;
+        }
+        if let Some(api_keys_file) = env_var(ENV_API_KEYS_FILE)? {
+            self.api_keys_file = Some(api_keys_file);
+        }
+        if let Some(max_subscriptions) = env_var(ENV_MAX_SUBSCRIPTIONS)? {
+            self.max_subscriptions = max_subscriptions;
+        }
+        if let Some(max_connections) = env_var(ENV_MAX_CONNECTIONS)? {
+            self.max_connections = max_connections;
+        }
+        if let Some(max_subscriptions_per_connection) = env_var(ENV_MAX_SUBSCRIPTIONS_PER_CONNECTION)? {
+            self.max_subscriptions_per_connection = max_subscriptions_per_connection;
+        }
+        if let Some(max_request_body_size_bytes) = env_var(ENV_MAX_REQUEST_BODY_SIZE_BYTES)? {
+            self.max_request_body_size_bytes = max_request_body_size_bytes;
+        }
+        if let Some(max_response_body_size_bytes) = env_var(ENV_MAX_RESPONSE_BODY_SIZE_BYTES)? {
+            self.max_response_body_size_bytes = max_response_body_size_bytes;
+        }
+        if let Some(ws_ping_interval_seconds) = env_var(ENV_WS_PING_INTERVAL_SECONDS)? {
+            self.ws_ping_interval_seconds = ws_ping_interval_seconds;
+        }
+        if let Some(drain_timeout_seconds) = env_var(ENV_DRAIN_TIMEOUT_SECONDS)? {
+            self.drain_timeout_seconds = drain_timeout_seconds;
+        }
+        Ok(self)
+    }
+
+    /// Settings that differ from `other` but are only applied on startup.
+    pub fn restart_required_changes(&self, other: &Config) -> Vec<&'static str> {
+        [
+            ("backend", self.backend != other.backend),
+            ("listen_addr", self.listen_addr != other.listen_addr),
+            ("tls_cert_file", self.tls_cert_file != other.tls_cert_file),
+            ("tls_key_file", self.tls_key_file != other.tls_key_file),
+            ("tls_client_ca_file", self.tls_client_ca_file != other.tls_client_ca_file),
+            ("metrics_listen_addr", self.metrics_listen_addr != other.metrics_listen_addr),
+            ("access_log", self.access_log != other.access_log),
+            ("health_check_interval_seconds", self.health_check_interval_seconds != other.health_check_interval_seconds),
+            ("cache_max_bytes", self.cache_max_bytes != other.cache_max_bytes),
+            ("cache_ttls_ms", self.cache_ttls_ms != other.cache_ttls_ms),
+            ("max_connections", self.max_connections != other.max_connections),
+            ("max_subscriptions_per_connection", self.max_subscriptions_per_connection != other.max_subscriptions_per_connection),
+            ("max_request_body_size_bytes", self.max_request_body_size_bytes != other.max_request_body_size_bytes),
+            ("max_response_body_size_bytes", self.max_response_body_size_bytes != other.max_response_body_size_bytes),
+            ("ws_ping_interval_seconds", self.ws_ping_interval_seconds != other.ws_ping_interval_seconds),
+            ("drain_timeout_seconds", self.drain_timeout_seconds != other.drain_timeout_seconds),
+        ]
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(setting, _)| setting)
+            .collect()
+    }
+
+    pub fn validated(self) -> Result<Self> {
+        if self.rpc_addrs.is_empty() {
+            return Err(anyhow!("missing required setting: rpc_addrs ({})", ENV_RPC_ADDR));
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err(anyhow!("tls_cert_file ({}) and tls_key_file ({}) must be set together", ENV_TLS_CERT_FILE, ENV_TLS_KEY_FILE));
+        }
+        if self.tls_client_ca_file.is_some() && self.tls_cert_file.is_none() {
+            return Err(anyhow!("tls_client_ca_file ({}) requires tls_cert_file and tls_key_file", ENV_TLS_CLIENT_CA_FILE));
+        }
+        if self.upstream_client_cert_file.is_some() != self.upstream_client_key_file.is_some() {
+            return Err(anyhow!(
+                "upstream_client_cert_file ({}) and upstream_client_key_file ({}) must be set together",
+                ENV_UPSTREAM_CLIENT_CERT_FILE,
+                ENV_UPSTREAM_CLIENT_KEY_FILE,
+            ));
+        }
+        if self.upstream_request_timeout_ms == 0 || self.upstream_connect_timeout_ms == 0 {
+            return Err(anyhow!(
+                "upstream_request_timeout_ms ({}) and upstream_connect_timeout_ms ({}) must be greater than 0",
+                ENV_UPSTREAM_REQUEST_TIMEOUT_MS,
+                ENV_UPSTREAM_CONNECT_TIMEOUT_MS,
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Config file path from the `--config` flag, falling back to `IGLTC_CONFIG`.
+fn config_path() -> Result<Option<String>> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(Some).ok_or(anyhow!("missing value for --config"));
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Ok(Some(path.to_string()));
+        }
     }
+    Ok(env::var(ENV_CONFIG).ok())
+}
+
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: Display,
+{
+    env::var(name)
+        .ok()
+        .map(|s| s.parse().map_err(|e| anyhow!("invalid value for {}: {}", name, e)))
+        .transpose()
+}
+
+/// Reads a comma-separated list.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|s| s.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect())
+}
+
+fn env_map<T: FromStr>(name: &str) -> Result<Option<HashMap<String, T>>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    env::var(name)
+        .ok()
+        .map(|s| parse_map(&s).map_err(|e| anyhow!("invalid value for {}: {}", name, e)))
+        .transpose()
+}
+
+/// Parses a comma-separated list of `key=value` pairs.
+fn parse_map<T: FromStr>(s: &str) -> Result<HashMap<String, T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    s.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let (k, v) = s.split_once('=').ok_or(anyhow!("expected key=value, got: {}", s))?;
+            Ok((k.trim().to_string(), v.trim().parse()?))
+        })
+        .collect()
 }
 
+pub const ENV_CONFIG: &str = "IGLTC_CONFIG";
 pub const ENV_BACKEND: &str = "IGLTC_BACKEND";
+pub const ENV_ALLOWED_ROUTES: &str = "IGLTC_ALLOWED_ROUTES";
 pub const ENV_BLOCKED_ROUTES: &str = "IGLTC_BLOCKED_ROUTES";
 pub const ENV_LISTEN_ADDR: &str = "IGLTC_LISTEN_ADDR";
+pub const ENV_TLS_CERT_FILE: &str = "IGLTC_TLS_CERT_FILE";
+pub const ENV_TLS_KEY_FILE: &str = "IGLTC_TLS_KEY_FILE";
+pub const ENV_TLS_CLIENT_CA_FILE: &str = "IGLTC_TLS_CLIENT_CA_FILE";
+pub const ENV_METRICS_LISTEN_ADDR: &str = "IGLTC_METRICS_LISTEN_ADDR";
+pub const ENV_ACCESS_LOG: &str = "IGLTC_ACCESS_LOG";
 pub const ENV_RPC_ADDR: &str = "IGLTC_RPC_ADDR";
+pub const ENV_ARCHIVE_RPC_ADDR: &str = "IGLTC_ARCHIVE_RPC_ADDR";
+pub const ENV_UPSTREAM_CA_FILE: &str = "IGLTC_UPSTREAM_CA_FILE";
+pub const ENV_UPSTREAM_CLIENT_CERT_FILE: &str = "IGLTC_UPSTREAM_CLIENT_CERT_FILE";
+pub const ENV_UPSTREAM_CLIENT_KEY_FILE: &str = "IGLTC_UPSTREAM_CLIENT_KEY_FILE";
+pub const ENV_UPSTREAM_HEADERS: &str = "IGLTC_UPSTREAM_HEADERS";
+pub const ENV_UPSTREAM_REQUEST_TIMEOUT_MS: &str = "IGLTC_UPSTREAM_REQUEST_TIMEOUT_MS";
+pub const ENV_UPSTREAM_CONNECT_TIMEOUT_MS: &str = "IGLTC_UPSTREAM_CONNECT_TIMEOUT_MS";
+pub const ENV_HEALTH_CHECK_INTERVAL_SECONDS: &str = "IGLTC_HEALTH_CHECK_INTERVAL_SECONDS";
+pub const ENV_MAX_BLOCK_LAG: &str = "IGLTC_MAX_BLOCK_LAG";
+pub const ENV_CACHE_MAX_BYTES: &str = "IGLTC_CACHE_MAX_BYTES";
+pub const ENV_CACHE_TTLS_MS: &str = "IGLTC_CACHE_TTLS_MS";
+pub const ENV_RATE_LIMIT_PER_SECOND: &str = "IGLTC_RATE_LIMIT_PER_SECOND";
+pub const ENV_RATE_LIMIT_BURST: &str = "IGLTC_RATE_LIMIT_BURST";
+pub const ENV_RATE_LIMIT_ROUTE_COSTS: &str = "IGLTC_RATE_LIMIT_ROUTE_COSTS";
+pub const ENV_PARAM_CAPS: &str = "IGLTC_PARAM_CAPS";
+pub const ENV_PARAM_CAP_MODE: &str = "IGLTC_PARAM_CAP_MODE";
+pub const ENV_API_KEYS_FILE: &str = "IGLTC_API_KEYS_FILE";
+pub const ENV_MAX_SUBSCRIPTIONS: &str = "IGLTC_MAX_SUBSCRIPTIONS";
 pub const ENV_MAX_CONNECTIONS: &str = "IGLTC_MAX_CONNECTIONS";
 pub const ENV_MAX_SUBSCRIPTIONS_PER_CONNECTION: &str = "IGLTC_MAX_SUBSCRIPTIONS_PER_CONNECTION";
 pub const ENV_MAX_REQUEST_BODY_SIZE_BYTES: &str = "IGLTC_MAX_REQUEST_BODY_SIZE_BYTES";
 pub const ENV_MAX_RESPONSE_BODY_SIZE_BYTES: &str = "IGLTC_MAX_RESPONSE_BODY_SIZE_BYTES";
 pub const ENV_WS_PING_INTERVAL_SECONDS: &str = "IGLTC_WS_PING_INTERVAL_SECONDS";
+pub const ENV_DRAIN_TIMEOUT_SECONDS: &str = "IGLTC_DRAIN_TIMEOUT_SECONDS";
 
 pub const DEFAULT_BACKEND: Backend = Backend::Comet34;
-pub const DEFAULT_BLOCKED_ROUTES: &str = "";
 pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+pub const DEFAULT_UPSTREAM_REQUEST_TIMEOUT_MS: u64 = 60_000;
+pub const DEFAULT_UPSTREAM_CONNECT_TIMEOUT_MS: u64 = 10_000;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL_SECONDS: u32 = 5;
+pub const DEFAULT_MAX_BLOCK_LAG: u64 = 10;
+pub const DEFAULT_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+pub const DEFAULT_CACHE_TTLS_MS: &str = "status=1000,health=1000,num_unconfirmed_txs=1000,abci_info=1000";
+pub const DEFAULT_RATE_LIMIT_PER_SECOND: u32 = 0;
+pub const DEFAULT_RATE_LIMIT_BURST: u32 = 50;
+pub const DEFAULT_RATE_LIMIT_ROUTE_COSTS: &str = "block_results=5,block_search=10,tx_search=10";
+pub const DEFAULT_PARAM_CAPS: &str = "unconfirmed_txs.limit=30,validators.per_page=100,blockchain.span=20";
+pub const DEFAULT_PARAM_CAP_MODE: CapMode = CapMode::Reject;
+pub const DEFAULT_MAX_SUBSCRIPTIONS: u32 = 10000;
 pub const DEFAULT_MAX_CONNECTIONS: u32 = 1000;
 pub const DEFAULT_MAX_SUBSCRIPTIONS_PER_CONNECTION: u32 = 5;
 pub const DEFAULT_MAX_REQUEST_BODY_SIZE_BYTES: u32 = 1024 * 1024;
 pub const DEFAULT_MAX_RESPONSE_BODY_SIZE_BYTES: u32 = 10 * 1024 * 1024;
 pub const DEFAULT_WS_PING_INTERVAL_SECONDS: u32 = 30;
+pub const DEFAULT_DRAIN_TIMEOUT_SECONDS: u32 = 30;
 
 lazy_static! {
-    pub static ref CONFIG: Config = Config::from_env().expect("failed reading environment config");
+    pub static ref CONFIG: Config = Config::load().expect("failed reading config");
 }
\ No newline at end of file
@@ -0,0 +1,138 @@
+use std::{
+	cell::{Cell, RefCell},
+	future::Future,
+	net::{IpAddr, SocketAddr},
+	sync::{Arc, Mutex, OnceLock},
+};
+use hyper::StatusCode;
+use tokio::sync::Notify;
+use jsonrpsee::server::logger::{HttpRequest, Logger, MethodKind, Params, TransportProtocol};
+use serde::Serialize;
+use crate::auth::api_key_from_request;
+use crate::metrics::WS_CONNECTIONS;
+use crate::tls::peer_addr;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+	Get,
+	Post,
+	WebSocket,
+}
+
+pub struct RequestContext {
+	pub client_ip: IpAddr,
+	pub api_key: Option<String>,
+	pub transport: Transport,
+	/// Notified by `unsubscribe_all` to end every subscription of the connection.
+	pub unsubscribed: Notify,
+	http_status: Mutex<Option<StatusCode>>,
+}
+
+impl RequestContext {
+	pub fn new(client_ip: IpAddr, api_key: Option<String>, transport: Transport) -> Self {
+		Self {
+			client_ip,
+			api_key,
+			transport,
+			unsubscribed: Notify::new(),
+			http_status: Mutex::new(None),
+		}
+	}
+
+	/// Status to respond with on the GET path, where a JSON-RPC error alone is easy to miss.
+	pub fn http_status(&self) -> Option<StatusCode> {
+		*self.http_status.lock().expect("context lock poisoned")
+	}
+
+	pub fn set_http_status(&self, status: StatusCode) {
+		*self.http_status.lock().expect("context lock poisoned") = Some(status);
+	}
+}
+
+tokio::task_local! {
+	pub static REQUEST_CONTEXT: Arc<RequestContext>;
+	static CALL_UPSTREAM: RefCell<Option<String>>;
+}
+
+thread_local! {
+	static CONNECTED_ADDR: Cell<Option<SocketAddr>> = const { Cell::new(None) };
+	static CONNECTION_REFUSED: Cell<bool> = const { Cell::new(false) };
+	static CALL_CONTEXT: RefCell<Option<Arc<RequestContext>>> = const { RefCell::new(None) };
+}
+
+/// Takes the remote address recorded by `ContextLogger::on_connect`, which jsonrpsee calls synchronously from its
+/// tower service, so it is available to middleware right after calling the inner service.
+pub fn take_connected_addr() -> Option<SocketAddr> {
+	CONNECTED_ADDR.with(|a| a.take())
+}
+
+/// Whether `ContextLogger::on_connect` refused the connection because it bypassed the TLS terminator, taken the same
+/// way as `take_connected_addr`.
+pub fn take_connection_refused() -> bool {
+	CONNECTION_REFUSED.with(|r| r.take())
+}
+
+/// Context of the call being handled. HTTP requests carry it in `REQUEST_CONTEXT`, websocket calls get it from the
+/// connection's logger, which hands it over in `on_call` right before the method callback is invoked.
+/// Must be called synchronously from the method callback.
+pub fn current_context() -> Option<Arc<RequestContext>> {
+	let ws_context = CALL_CONTEXT.with(|c| c.borrow_mut().take());
+	REQUEST_CONTEXT.try_with(Arc::clone).ok().or(ws_context)
+}
+
+/// Runs a call while recording which upstream ends up answering it.
+pub async fn with_call_upstream<F: Future>(fut: F) -> (F::Output, Option<String>) {
+	CALL_UPSTREAM.scope(RefCell::new(None), async {
+		let res = fut.await;
+		(res, CALL_UPSTREAM.with(|u| u.borrow_mut().take()))
+	}).await
+}
+
+/// Records the upstream the current call was sent to, a no-op outside `with_call_upstream`.
+pub fn set_call_upstream(url: &str) {
+	let _ = CALL_UPSTREAM.try_with(|u| *u.borrow_mut() = Some(url.to_string()));
+}
+
+/// Logger that tracks websocket connection context. jsonrpsee clones the logger for every connection and clones the
+/// connection's logger into its websocket task after `on_connect`, so each websocket task ends up with its own context.
+#[derive(Clone, Default)]
+pub struct ContextLogger {
+	ws_context: OnceLock<Arc<RequestContext>>,
+}
+
+impl Logger for ContextLogger {
+	type Instant = ();
+
+	fn on_connect(&self, remote_addr: SocketAddr, request: &HttpRequest, transport: TransportProtocol) {
+		let remote_addr = match peer_addr(remote_addr) {
+			Some(addr) => addr,
+			None => {
+				tracing::warn!("refused connection from {} bypassing TLS", remote_addr);
+				CONNECTION_REFUSED.with(|r| r.set(true));
+				return;
+			},
+		};
+		CONNECTED_ADDR.with(|a| a.set(Some(remote_addr)));
+		if let TransportProtocol::WebSocket = transport {
+			let _ = self.ws_context.set(Arc::new(RequestContext::new(remote_addr.ip(), api_key_from_request(request), Transport::WebSocket)));
+			WS_CONNECTIONS.inc();
+		}
+	}
+
+	fn on_request(&self, _transport: TransportProtocol) -> Self::Instant {}
+
+	fn on_call(&self, _method_name: &str, _params: Params, _kind: MethodKind, _transport: TransportProtocol) {
+		CALL_CONTEXT.with(|c| *c.borrow_mut() = self.ws_context.get().cloned());
+	}
+
+	fn on_result(&self, _method_name: &str, _success: bool, _started_at: Self::Instant, _transport: TransportProtocol) {}
+
+	fn on_response(&self, _result: &str, _started_at: Self::Instant, _transport: TransportProtocol) {}
+
+	fn on_disconnect(&self, _remote_addr: SocketAddr, transport: TransportProtocol) {
+		if let TransportProtocol::WebSocket = transport {
+			WS_CONNECTIONS.dec();
+		}
+	}
+}
@@ -0,0 +1,60 @@
+use std::{sync::atomic::{AtomicBool, AtomicUsize, Ordering}, time::Duration};
+use tokio::{sync::Notify, time::sleep};
+
+/// Tracks in-flight HTTP requests so shutdown can wait for them, and refuses new requests once draining.
+#[derive(Default)]
+pub struct Drain {
+	draining: AtomicBool,
+	in_flight: AtomicUsize,
+	websockets_closed: AtomicBool,
+	close_websockets: Notify,
+}
+
+impl Drain {
+	pub fn is_draining(&self) -> bool {
+		self.draining.load(Ordering::Relaxed)
+	}
+
+	pub fn start(&self) {
+		self.draining.store(true, Ordering::Relaxed);
+	}
+
+	/// Registers a request until the returned guard is dropped, returns `None` when draining.
+	pub fn track(&'static self) -> Option<InFlightGuard> {
+		self.in_flight.fetch_add(1, Ordering::SeqCst);
+		let guard = InFlightGuard(self);
+		match self.is_draining() {
+			true => None,
+			false => Some(guard),
+		}
+	}
+
+	/// Tells websocket sessions served outside jsonrpsee to close, which jsonrpsee does for its own when stopped.
+	pub fn close_websockets(&self) {
+		self.websockets_closed.store(true, Ordering::Relaxed);
+		self.close_websockets.notify_waiters();
+	}
+
+	pub async fn websockets_closed(&self) {
+		let notified = self.close_websockets.notified();
+		tokio::pin!(notified);
+		notified.as_mut().enable();
+		if !self.websockets_closed.load(Ordering::Relaxed) {
+			notified.await;
+		}
+	}
+
+	pub async fn wait_idle(&self) {
+		while self.in_flight.load(Ordering::SeqCst) > 0 {
+			sleep(Duration::from_millis(50)).await;
+		}
+	}
+}
+
+pub struct InFlightGuard(&'static Drain);
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+	}
+}
@@ -1,7 +1,19 @@
+mod accesslog;
+mod auth;
 mod backend;
+mod cache;
 mod config;
+mod context;
+mod drain;
+mod metrics;
 mod proxy;
+mod query;
+mod ratelimit;
+mod subscription;
 mod comet34;
+mod tls;
+mod upstream;
+mod websocket;
 
 use std::env;
 use anyhow::Result;
@@ -0,0 +1,86 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr};
+use anyhow::Result;
+use hyper::{
+	header::CONTENT_TYPE,
+	service::{make_service_fn, service_fn},
+	Body, Method, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+	register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+	Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+	pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+		"igltc_requests_total",
+		"RPC calls by route and result",
+		&["method", "result"]
+	).expect("failed registering metric");
+	pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+		"igltc_request_duration_seconds",
+		"RPC call latency by route",
+		&["method"]
+	).expect("failed registering metric");
+	pub static ref UPSTREAM_ERRORS: IntCounterVec = register_int_counter_vec!(
+		"igltc_upstream_errors_total",
+		"Failed upstream requests by upstream and route",
+		&["upstream", "method"]
+	).expect("failed registering metric");
+	pub static ref BLOCKED_ROUTE_HITS: IntCounterVec = register_int_counter_vec!(
+		"igltc_blocked_route_hits_total",
+		"Calls rejected by route policies",
+		&["method"]
+	).expect("failed registering metric");
+	pub static ref WS_CONNECTIONS: IntGauge = register_int_gauge!(
+		"igltc_websocket_connections",
+		"Open websocket connections"
+	).expect("failed registering metric");
+	pub static ref SUBSCRIPTIONS: IntGaugeVec = register_int_gauge_vec!(
+		"igltc_subscriptions",
+		"Active websocket subscriptions by route",
+		&["method"]
+	).expect("failed registering metric");
+	pub static ref UPSTREAM_SUBSCRIPTIONS: IntGauge = register_int_gauge!(
+		"igltc_upstream_subscriptions",
+		"Upstream subscriptions shared by the clients subscribed to the same query"
+	).expect("failed registering metric");
+	pub static ref ACCESS_LOG_DROPPED: IntCounter = register_int_counter!(
+		"igltc_access_log_dropped_total",
+		"Access log lines dropped because the writer fell behind"
+	).expect("failed registering metric");
+}
+
+/// Registers all metrics and the label values of every route, so they are exported before the first call.
+pub fn init<'a>(routes: impl Iterator<Item = &'a str>) {
+	lazy_static::initialize(&UPSTREAM_ERRORS);
+	lazy_static::initialize(&WS_CONNECTIONS);
+	lazy_static::initialize(&SUBSCRIPTIONS);
+	lazy_static::initialize(&UPSTREAM_SUBSCRIPTIONS);
+	lazy_static::initialize(&ACCESS_LOG_DROPPED);
+	for method in routes {
+		REQUESTS.with_label_values(&[method, "ok"]);
+		REQUESTS.with_label_values(&[method, "error"]);
+		REQUEST_DURATION.with_label_values(&[method]);
+		BLOCKED_ROUTE_HITS.with_label_values(&[method]);
+	}
+}
+
+/// Binds the `/metrics` listener, kept separate from the RPC server so upstream addresses in labels aren't public.
+pub fn server(addr: SocketAddr) -> Result<impl Future<Output = hyper::Result<()>>> {
+	let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+	Ok(Server::try_bind(&addr)?.serve(make_service))
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+	if req.method() != Method::GET || req.uri().path() != "/metrics" {
+		return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).expect("valid response"));
+	}
+	let encoder = TextEncoder::new();
+	let mut buf = vec![];
+	let res = match encoder.encode(&prometheus::gather(), &mut buf) {
+		Ok(()) => Response::builder().header(CONTENT_TYPE, encoder.format_type()).body(Body::from(buf)),
+		Err(e) => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())),
+	};
+	Ok(res.expect("valid response"))
+}
@@ -7,13 +7,96 @@ use std::{
 	task::{Context, Poll},
 };
 use hyper::{
-	header::{ACCEPT, CONTENT_TYPE},
+	header::{ACCEPT, CONNECTION, CONTENT_TYPE},
 	http::HeaderValue,
 	Body, Method, Request, Response, Uri, StatusCode,
 };
 use jsonrpsee::types::{Id, RequestSer};
 use serde_json::{value::to_raw_value, Value as JsonValue};
+use soketto::handshake::http::is_upgrade_request;
 use tower::{Layer, Service};
+use crate::auth::{api_key_from_request, API_KEY_QUERY_PARAM};
+use crate::context::{take_connected_addr, take_connection_refused, RequestContext, Transport, REQUEST_CONTEXT};
+use crate::drain::Drain;
+
+/// Paths websocket upgrades are served on, `/websocket` being the one CometBFT serves them on.
+const WEBSOCKET_PATHS: [&str; 2] = ["/", "/websocket"];
+
+#[derive(Clone)]
+pub struct RequestContextLayer {
+	drain: &'static Drain,
+}
+
+impl RequestContextLayer {
+	pub fn new(drain: &'static Drain) -> Self {
+		Self { drain }
+	}
+}
+
+impl<S> Layer<S> for RequestContextLayer {
+	type Service = RequestContextService<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		RequestContextService::new(inner, self.drain)
+	}
+}
+
+#[derive(Clone)]
+pub struct RequestContextService<S> {
+	inner: S,
+	drain: &'static Drain,
+}
+
+impl<S> RequestContextService<S> {
+	pub fn new(inner: S, drain: &'static Drain) -> Self {
+		Self { inner, drain }
+	}
+}
+
+impl<S> Service<Request<Body>> for RequestContextService<S>
+where
+	S: Service<Request<Body>, Response = Response<Body>>,
+	S::Response: 'static,
+	S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+	S::Future: Send + 'static,
+{
+	type Response = S::Response;
+	type Error = Box<dyn Error + Send + Sync + 'static>;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+	#[inline]
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx).map_err(Into::into)
+	}
+
+	fn call(&mut self, req: Request<Body>) -> Self::Future {
+		let in_flight = match self.drain.track() {
+			Some(guard) => guard,
+			None => return Box::pin(async {
+				Ok(Response::builder()
+					.status(StatusCode::SERVICE_UNAVAILABLE)
+					.header(CONNECTION, "close")
+					.body(Body::from("shutting down"))
+					.expect("valid response"))
+			}),
+		};
+		let api_key = api_key_from_request(&req);
+		let transport = match *req.method() == Method::GET {
+			true => Transport::Get,
+			false => Transport::Post,
+		};
+		let fut = self.inner.call(req);
+		let res_fut = async move {
+			let res = fut.await.map_err(|err| err.into());
+			drop(in_flight);
+			res
+		};
+		match take_connected_addr() {
+			Some(addr) => Box::pin(REQUEST_CONTEXT.scope(Arc::new(RequestContext::new(addr.ip(), api_key, transport)), res_fut)),
+			None => Box::pin(res_fut),
+		}
+	}
+}
 
 #[derive(Debug, Clone)]
 pub struct ProxyGetRequestParamsLayer {}
@@ -60,17 +143,27 @@ where
 	}
 
 	fn call(&mut self, mut req: Request<Body>) -> Self::Future {
-		let modify = req.uri().path() != "/" && req.method() == Method::GET;
+		if is_upgrade_request(&req) && !WEBSOCKET_PATHS.contains(&req.uri().path()) {
+			return Box::pin(async {
+				Ok(Response::builder()
+					.status(StatusCode::NOT_FOUND)
+					.header(CONNECTION, "close")
+					.body(Body::from("websocket connections are served on / and /websocket"))
+					.expect("valid response"))
+			});
+		}
+		let modify = req.uri().path() != "/" && req.method() == Method::GET && !is_upgrade_request(&req);
 		if modify {
 			let params_map: HashMap<String, JsonValue> = req
 				.uri()
 				.query()
 				.map(|v| url::form_urlencoded::parse(v.as_bytes())
 					.into_owned()
+					.filter(|(k, _)| k != API_KEY_QUERY_PARAM)
 					.map(|(k, v)| (k, serde_json::to_value(v.trim_matches('"')).expect("valid query param")))
 					.collect()
 				)
-				.unwrap_or_else(HashMap::new);
+				.unwrap_or_default();
 			let params_raw = to_raw_value(&params_map).expect("valid params");
 			let method = req.uri().path().trim_start_matches('/').to_string();
 			*req.method_mut() = Method::POST;
@@ -85,7 +178,11 @@ where
 		}
 		let fut = self.inner.call(req);
 		let res_fut = async move {
-			Ok(fut.await.map_err(|err| err.into())?)
+			let mut res = fut.await.map_err(|err| err.into())?;
+			if let Some(status) = REQUEST_CONTEXT.try_with(|c| c.http_status()).ok().flatten().filter(|_| modify) {
+				*res.status_mut() = status;
+			}
+			Ok(res)
         };
         Box::pin(res_fut)
     }
@@ -94,13 +191,13 @@ where
 #[derive(Clone)]
 pub struct ProxyGetRequestCustomLayer {
 	path: String,
-	func: &'static dyn Fn(&Request<Body>) -> String,
+	func: &'static dyn Fn(&Request<Body>) -> (StatusCode, String),
 }
 
 unsafe impl Send for ProxyGetRequestCustomLayer {}
 
 impl ProxyGetRequestCustomLayer {
-	pub fn new(path: impl Into<String>, func: &'static impl Fn(&Request<Body>) -> String) -> Result<Self, jsonrpsee::core::Error> {
+	pub fn new(path: impl Into<String>, func: &'static impl Fn(&Request<Body>) -> (StatusCode, String)) -> Result<Self, jsonrpsee::core::Error> {
 		let path = path.into();
 		if !path.starts_with('/') {
 			return Err(jsonrpsee::core::Error::Custom("ProxyGetRequestCustomLayer path must start with `/`".to_string()));
@@ -122,13 +219,13 @@ impl<S> Layer<S> for ProxyGetRequestCustomLayer {
 pub struct ProxyGetRequestCustom<S> {
 	inner: S,
 	path: Arc<str>,
-	func: &'static dyn Fn(&Request<Body>) -> String,
+	func: &'static dyn Fn(&Request<Body>) -> (StatusCode, String),
 }
 
 unsafe impl<S> Send for ProxyGetRequestCustom<S> {}
 
 impl<S> ProxyGetRequestCustom<S> {
-	pub fn new(inner: S, path: &str, func: &'static dyn Fn(&Request<Body>) -> String) -> Result<Self, jsonrpsee::core::Error> {
+	pub fn new(inner: S, path: &str, func: &'static dyn Fn(&Request<Body>) -> (StatusCode, String)) -> Result<Self, jsonrpsee::core::Error> {
 		if !path.starts_with('/') {
 			return Err(jsonrpsee::core::Error::Custom(format!("ProxyGetRequestCustom path must start with `/`, got: {}", path)));
 		}
@@ -154,12 +251,12 @@ where
 	}
 
 	fn call(&mut self, req: Request<Body>) -> Self::Future {
-		let modify = self.path.as_ref() == req.uri().path() && req.method() == Method::GET;
+		let modify = self.path.as_ref() == req.uri().path() && req.method() == Method::GET && !is_upgrade_request(&req);
 		if modify {
-			let content = (self.func)(&req);
+			let (status, content) = (self.func)(&req);
 			let res_fut = async move {
 				Response::builder()
-					.status(StatusCode::OK)
+					.status(status)
 					.body(Body::from(content))
 					.map_err(Into::into)
 			};
@@ -167,8 +264,58 @@ where
 		}
 		let fut = self.inner.call(req);
 		let res_fut = async move {
-			Ok(fut.await.map_err(|err| err.into())?)
+			fut.await.map_err(|err| err.into())
         };
         Box::pin(res_fut)
     }
 }
+
+/// Fails requests on connections `ContextLogger::on_connect` refused, must wrap the jsonrpsee service directly since
+/// the refusal is only known right after calling it.
+#[derive(Debug, Clone)]
+pub struct RefuseConnectionLayer {}
+
+impl RefuseConnectionLayer {
+	pub fn new() -> Self {
+		Self {}
+	}
+}
+
+impl<S> Layer<S> for RefuseConnectionLayer {
+	type Service = RefuseConnection<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		RefuseConnection { inner }
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RefuseConnection<S> {
+	inner: S,
+}
+
+impl<S> Service<Request<Body>> for RefuseConnection<S>
+where
+	S: Service<Request<Body>, Response = Response<Body>>,
+	S::Response: 'static,
+	S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+	S::Future: Send + 'static,
+{
+	type Response = S::Response;
+	type Error = Box<dyn Error + Send + Sync + 'static>;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+	#[inline]
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx).map_err(Into::into)
+	}
+
+	fn call(&mut self, req: Request<Body>) -> Self::Future {
+		let fut = self.inner.call(req);
+		if take_connection_refused() {
+			// failing the service closes the connection, a response would let hyper complete a websocket upgrade anyway
+			return Box::pin(async { Err("connection bypassed TLS".into()) });
+		}
+		Box::pin(async move { fut.await.map_err(Into::into) })
+	}
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Operator {
+	#[serde(rename = "=")]
+	Eq,
+	#[serde(rename = "<")]
+	Lt,
+	#[serde(rename = "<=")]
+	Le,
+	#[serde(rename = ">")]
+	Gt,
+	#[serde(rename = ">=")]
+	Ge,
+	#[serde(rename = "CONTAINS")]
+	Contains,
+	#[serde(rename = "EXISTS")]
+	Exists,
+}
+
+impl fmt::Display for Operator {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Operator::Eq => "=",
+			Operator::Lt => "<",
+			Operator::Le => "<=",
+			Operator::Gt => ">",
+			Operator::Ge => ">=",
+			Operator::Contains => "CONTAINS",
+			Operator::Exists => "EXISTS",
+		})
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+	String(String),
+	Number(String),
+	Date(String),
+	Time(String),
+}
+
+impl Operand {
+	pub fn as_u64(&self) -> Option<u64> {
+		match self {
+			Operand::Number(n) => n.parse().ok(),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for Operand {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Operand::String(s) => write!(f, "'{}'", s),
+			Operand::Number(n) => f.write_str(n),
+			Operand::Date(d) => write!(f, "DATE {}", d),
+			Operand::Time(t) => write!(f, "TIME {}", t),
+		}
+	}
+}
+
+/// Single condition of a query, `operand` is only absent for `EXISTS`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+	pub key: String,
+	pub operator: Operator,
+	pub operand: Option<Operand>,
+}
+
+impl fmt::Display for Condition {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.operand {
+			Some(operand) => write!(f, "{} {} {}", self.key, self.operator, operand),
+			None => write!(f, "{} {}", self.key, self.operator),
+		}
+	}
+}
+
+/// CometBFT event query, conditions joined by `AND`. Displays in a canonical form with normalized whitespace and
+/// conditions sorted, so queries differing only in those display the same.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+	pub conditions: Vec<Condition>,
+}
+
+impl Query {
+	/// Parses the query language of CometBFT's `tx_search`, `block_search` and `subscribe`.
+	pub fn parse(query: &str) -> Result<Self> {
+		let mut parser = Parser { input: query.trim().trim_matches('"'), pos: 0 };
+		let mut conditions = vec![parser.condition()?];
+		loop {
+			parser.skip_whitespace();
+			if parser.at_end() {
+				break;
+			}
+			if !parser.keyword("AND") {
+				return Err(parser.error("expected AND"));
+			}
+			conditions.push(parser.condition()?);
+		}
+		Ok(Self { conditions })
+	}
+
+	pub fn conditions_on<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Condition> {
+		self.conditions.iter().filter(move |c| c.key == key)
+	}
+}
+
+impl fmt::Display for Query {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut conditions = self.conditions.iter().map(Condition::to_string).collect::<Vec<_>>();
+		conditions.sort();
+		f.write_str(&conditions.join(" AND "))
+	}
+}
+
+struct Parser<'a> {
+	input: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn rest(&self) -> &'a str {
+		&self.input[self.pos..]
+	}
+
+	fn at_end(&self) -> bool {
+		self.pos == self.input.len()
+	}
+
+	fn error(&self, expected: &str) -> anyhow::Error {
+		anyhow!("invalid query at position {}: {}", self.pos, expected)
+	}
+
+	fn skip_whitespace(&mut self) {
+		let rest = self.rest();
+		self.pos += rest.len() - rest.trim_start().len();
+	}
+
+	fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+		let rest = self.rest();
+		let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+		self.pos += len;
+		&rest[..len]
+	}
+
+	/// Consumes `word` if it is next and not followed by more of the same token.
+	fn keyword(&mut self, word: &str) -> bool {
+		let rest = self.rest();
+		let matches = rest.starts_with(word) && rest[word.len()..].chars().next().is_none_or(|c| !is_key_char(c));
+		if matches {
+			self.pos += word.len();
+		}
+		matches
+	}
+
+	fn condition(&mut self) -> Result<Condition> {
+		self.skip_whitespace();
+		let key = self.take_while(is_key_char).to_string();
+		if key.is_empty() {
+			return Err(self.error("expected key"));
+		}
+		self.skip_whitespace();
+		let operator = if self.keyword("EXISTS") {
+			return Ok(Condition { key, operator: Operator::Exists, operand: None });
+		} else if self.keyword("CONTAINS") {
+			Operator::Contains
+		} else {
+			let operator = [("<=", Operator::Le), (">=", Operator::Ge), ("=", Operator::Eq), ("<", Operator::Lt), (">", Operator::Gt)]
+				.into_iter()
+				.find(|(op, _)| self.rest().starts_with(op));
+			match operator {
+				Some((op, operator)) => {
+					self.pos += op.len();
+					operator
+				},
+				None => return Err(self.error("expected operator")),
+			}
+		};
+		self.skip_whitespace();
+		let operand = self.operand()?;
+		if operator == Operator::Contains && !matches!(operand, Operand::String(_)) {
+			return Err(anyhow!("CONTAINS requires a string operand"));
+		}
+		Ok(Condition { key, operator, operand: Some(operand) })
+	}
+
+	fn operand(&mut self) -> Result<Operand> {
+		if let Some(rest) = self.rest().strip_prefix('\'') {
+			let len = rest.find('\'').ok_or_else(|| self.error("unterminated string"))?;
+			self.pos += len + 2;
+			return Ok(Operand::String(rest[..len].to_string()));
+		}
+		if self.keyword("DATE") {
+			self.skip_whitespace();
+			let date = self.take_while(|c| !c.is_whitespace());
+			if !is_date(date) {
+				return Err(self.error("expected DATE in the form YYYY-MM-DD"));
+			}
+			return Ok(Operand::Date(date.to_string()));
+		}
+		if self.keyword("TIME") {
+			self.skip_whitespace();
+			let time = self.take_while(|c| !c.is_whitespace());
+			if !is_time(time) {
+				return Err(self.error("expected TIME in RFC3339 form"));
+			}
+			return Ok(Operand::Time(time.to_string()));
+		}
+		let number = self.take_while(|c| c.is_ascii_digit() || c == '.' || c == '-');
+		let digits = number.strip_prefix('-').unwrap_or(number);
+		let valid = match digits.split_once('.') {
+			Some((int, frac)) => is_digits(int) && is_digits(frac),
+			None => is_digits(digits),
+		};
+		if !valid {
+			return Err(self.error("expected string, number, DATE or TIME"));
+		}
+		Ok(Operand::Number(number.to_string()))
+	}
+}
+
+fn is_key_char(c: char) -> bool {
+	!c.is_whitespace() && !matches!(c, '=' | '<' | '>' | '\'' | '"' | '(' | ')' | '\\')
+}
+
+fn is_digits(s: &str) -> bool {
+	!s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_date(s: &str) -> bool {
+	let parts = s.split('-').collect::<Vec<_>>();
+	parts.len() == 3 && parts[0].len() == 4 && parts[1].len() == 2 && parts[2].len() == 2 && parts.iter().all(|p| is_digits(p))
+}
+
+fn is_time(s: &str) -> bool {
+	let Some((date, time)) = s.split_once('T') else {
+		return false;
+	};
+	let (Some(clock), Some(zone)) = (time.get(..8), time.get(8..)) else {
+		return false;
+	};
+	is_date(date)
+		&& clock.split(':').count() == 3
+		&& clock.split(':').all(|p| p.len() == 2 && is_digits(p))
+		&& (zone == "Z" || zone.starts_with(['.', '+', '-']))
+}
+
+pub fn validate_tx_hash(hash: &str) -> Result<()> {
+	if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+		return Err(anyhow!("tx.hash must be 64 characters long and contain only hex digits"));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn condition(key: &str, operator: Operator, operand: Option<Operand>) -> Condition {
+		Condition { key: key.to_string(), operator, operand }
+	}
+
+	#[test]
+	fn parse_chains_conditions_with_every_operator() {
+		let query = Query::parse("a = 1 AND b < 2 AND c <= 3 AND d > 4 AND e >= 5 AND f CONTAINS 'x' AND g EXISTS").unwrap();
+		assert_eq!(query.conditions, vec![
+			condition("a", Operator::Eq, Some(Operand::Number("1".to_string()))),
+			condition("b", Operator::Lt, Some(Operand::Number("2".to_string()))),
+			condition("c", Operator::Le, Some(Operand::Number("3".to_string()))),
+			condition("d", Operator::Gt, Some(Operand::Number("4".to_string()))),
+			condition("e", Operator::Ge, Some(Operand::Number("5".to_string()))),
+			condition("f", Operator::Contains, Some(Operand::String("x".to_string()))),
+			condition("g", Operator::Exists, None),
+		]);
+	}
+
+	#[test]
+	fn parse_accepts_compact_and_quoted_queries() {
+		let query = Query::parse("\"tx.height>=5 AND message.sender='cosmos1abc'\"").unwrap();
+		assert_eq!(query.conditions, vec![
+			condition("tx.height", Operator::Ge, Some(Operand::Number("5".to_string()))),
+			condition("message.sender", Operator::Eq, Some(Operand::String("cosmos1abc".to_string()))),
+		]);
+		assert_eq!(query.conditions_on("tx.height").count(), 1);
+	}
+
+	#[test]
+	fn parse_reads_dates_and_times() {
+		let query = Query::parse("block.date >= DATE 2024-01-02 AND block.time < TIME 2024-01-02T15:04:05Z").unwrap();
+		assert_eq!(query.conditions[0].operand, Some(Operand::Date("2024-01-02".to_string())));
+		assert_eq!(query.conditions[1].operand, Some(Operand::Time("2024-01-02T15:04:05Z".to_string())));
+		assert!(Query::parse("block.time < TIME 2024-01-02T15:04:05.123+02:00").is_ok());
+		assert!(Query::parse("block.date = DATE 2024-1-2").is_err());
+		assert!(Query::parse("block.time = TIME 2024-01-02T15:04:05").is_err());
+	}
+
+	#[test]
+	fn parse_reads_negative_and_decimal_numbers() {
+		let query = Query::parse("a = -5 AND b > 1.5 AND c < -0.25").unwrap();
+		let operands = query.conditions.iter().map(|c| c.operand.clone().unwrap()).collect::<Vec<_>>();
+		assert_eq!(operands, vec![
+			Operand::Number("-5".to_string()),
+			Operand::Number("1.5".to_string()),
+			Operand::Number("-0.25".to_string()),
+		]);
+		assert_eq!(operands[0].as_u64(), None);
+		assert_eq!(operands[1].as_u64(), None);
+		assert!(Query::parse("a = 1.").is_err());
+		assert!(Query::parse("a = --1").is_err());
+		assert!(Query::parse("a = 1.2.3").is_err());
+	}
+
+	#[test]
+	fn parse_rejects_contains_without_string() {
+		assert!(Query::parse("a CONTAINS 5").is_err());
+		assert!(Query::parse("a CONTAINS DATE 2024-01-02").is_err());
+		assert!(Query::parse("a CONTAINS 'b'").is_ok());
+	}
+
+	#[test]
+	fn parse_rejects_malformed_queries() {
+		for query in [
+			"",
+			"a = 'b",
+			"a = 'b' AND c = 'd",
+			"a = 1 b = 2",
+			"a = 1 AND",
+			"a = 1 ANDb = 2",
+			"a = 1 OR b = 2",
+			"a = 1)",
+			"a == 1",
+			"a",
+			"= 1",
+			"a = b",
+			"a EXISTSX",
+		] {
+			assert!(Query::parse(query).is_err(), "{}", query);
+		}
+	}
+
+	#[test]
+	fn display_is_canonical() {
+		let query = Query::parse("  tm.event='Tx'AND tx.height>=5   AND a EXISTS AND d > DATE 2024-01-02 AND t < TIME 2024-01-02T15:04:05Z").unwrap();
+		assert_eq!(
+			query.to_string(),
+			"a EXISTS AND d > DATE 2024-01-02 AND t < TIME 2024-01-02T15:04:05Z AND tm.event = 'Tx' AND tx.height >= 5",
+		);
+		assert_eq!(
+			Query::parse("b = 2 AND a = 1").unwrap().to_string(),
+			Query::parse("a=1 AND b=2").unwrap().to_string(),
+		);
+	}
+
+	#[test]
+	fn validate_tx_hash_requires_64_hex_digits() {
+		assert!(validate_tx_hash(&"aB".repeat(32)).is_ok());
+		assert!(validate_tx_hash(&"a".repeat(63)).is_err());
+		assert!(validate_tx_hash(&"g".repeat(64)).is_err());
+	}
+}
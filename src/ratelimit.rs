@@ -0,0 +1,165 @@
+use std::{
+	collections::HashMap,
+	hash::Hash,
+	net::IpAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+pub const RATE_LIMITED_CODE: i32 = -32029;
+
+struct Bucket {
+	tokens: f64,
+	updated_at: Instant,
+}
+
+struct Buckets<K> {
+	by_client: HashMap<K, Bucket>,
+	pruned_at: Instant,
+}
+
+/// Token bucket rate limiter keyed by client IP (or any other client key), where each route costs a configurable
+/// number of tokens.
+pub struct RateLimiter<K = IpAddr> {
+	pub per_second: u32,
+	pub burst: u32,
+	pub route_costs: HashMap<String, u32>,
+	buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+	const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+	pub fn new(per_second: u32, burst: u32, route_costs: HashMap<String, u32>) -> Self {
+		Self {
+			per_second,
+			burst: burst.max(1),
+			route_costs,
+			buckets: Mutex::new(Buckets { by_client: HashMap::new(), pruned_at: Instant::now() }),
+		}
+	}
+
+	/// Builds a rate limiter, reusing `previous` if its limits are unchanged so clients keep their buckets across reloads.
+	pub fn new_or_reuse(per_second: u32, burst: u32, route_costs: HashMap<String, u32>, previous: Option<&Arc<Self>>) -> Arc<Self> {
+		match previous {
+			Some(limiter) if limiter.per_second == per_second && limiter.burst == burst.max(1) && limiter.route_costs == route_costs => {
+				limiter.clone()
+			},
+			_ => Arc::new(Self::new(per_second, burst, route_costs)),
+		}
+	}
+
+	pub fn cost(&self, method: &str) -> u32 {
+		self.route_costs.get(method).copied().unwrap_or(1)
+	}
+
+	/// Errors if a route costs more than a full bucket, which would make it impossible to call.
+	pub fn validate(&self) -> anyhow::Result<()> {
+		match self.route_costs.iter().find(|(_, cost)| self.per_second > 0 && **cost > self.burst) {
+			Some((route, cost)) => Err(anyhow::anyhow!("rate limit cost for {} exceeds burst: {} > {}", route, cost, self.burst)),
+			None => Ok(()),
+		}
+	}
+
+	/// Takes the route's cost from the client's bucket, returns false if there are not enough tokens left.
+	pub fn check(&self, client: K, method: &str) -> bool {
+		if self.per_second == 0 {
+			return true;
+		}
+		let now = Instant::now();
+		let cost = self.cost(method) as f64;
+		let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+		if now.duration_since(buckets.pruned_at) > Self::PRUNE_INTERVAL {
+			let (burst, per_second) = (self.burst as f64, self.per_second as f64);
+			buckets.by_client.retain(|_, b| b.tokens + now.duration_since(b.updated_at).as_secs_f64() * per_second < burst);
+			buckets.pruned_at = now;
+		}
+		let bucket = buckets.by_client.entry(client).or_insert(Bucket { tokens: self.burst as f64, updated_at: now });
+		bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * self.per_second as f64)
+			.min(self.burst as f64);
+		bucket.updated_at = now;
+		if bucket.tokens < cost {
+			return false;
+		}
+		bucket.tokens -= cost;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn limiter(per_second: u32, burst: u32) -> RateLimiter<&'static str> {
+		RateLimiter::new(per_second, burst, HashMap::from([("tx_search".to_string(), 3)]))
+	}
+
+	fn age(limiter: &RateLimiter<&'static str>, by: Duration) {
+		let mut buckets = limiter.buckets.lock().unwrap();
+		buckets.pruned_at -= by;
+		for bucket in buckets.by_client.values_mut() {
+			bucket.updated_at -= by;
+		}
+	}
+
+	#[test]
+	fn check_refills_over_time() {
+		let limiter = limiter(2, 2);
+		assert!(limiter.check("a", "status"));
+		assert!(limiter.check("a", "status"));
+		assert!(!limiter.check("a", "status"));
+		age(&limiter, Duration::from_millis(600));
+		assert!(limiter.check("a", "status"));
+		assert!(!limiter.check("a", "status"));
+		age(&limiter, Duration::from_secs(10));
+		assert!(limiter.check("a", "status"));
+		assert!(limiter.check("a", "status"));
+		assert!(!limiter.check("a", "status"));
+	}
+
+	#[test]
+	fn check_rejects_cost_above_remaining_tokens() {
+		let limiter = limiter(1, 4);
+		assert!(limiter.check("a", "status"));
+		assert!(limiter.check("a", "status"));
+		assert!(!limiter.check("a", "tx_search"));
+		assert!(limiter.check("a", "status"));
+		assert!(limiter.check("b", "tx_search"));
+	}
+
+	#[test]
+	fn check_prunes_full_buckets() {
+		let limiter = limiter(1, 2);
+		assert!(limiter.check("a", "status"));
+		assert!(limiter.check("b", "status"));
+		age(&limiter, Duration::from_secs(61));
+		assert!(limiter.check("c", "status"));
+		let buckets = limiter.buckets.lock().unwrap();
+		assert!(buckets.by_client.contains_key("c"));
+		assert!(!buckets.by_client.contains_key("a"));
+		assert!(!buckets.by_client.contains_key("b"));
+	}
+
+	#[test]
+	fn new_or_reuse_keeps_buckets_with_same_limits() {
+		let previous = Arc::new(limiter(1, 2));
+		assert!(previous.check("a", "status"));
+		assert!(previous.check("a", "status"));
+		let reused = RateLimiter::new_or_reuse(1, 2, previous.route_costs.clone(), Some(&previous));
+		assert!(Arc::ptr_eq(&reused, &previous));
+		assert!(!reused.check("a", "status"));
+		let rebuilt = RateLimiter::new_or_reuse(1, 3, previous.route_costs.clone(), Some(&previous));
+		assert!(!Arc::ptr_eq(&rebuilt, &previous));
+		assert!(rebuilt.check("a", "status"));
+		let recosted = RateLimiter::new_or_reuse(1, 2, HashMap::new(), Some(&previous));
+		assert!(!Arc::ptr_eq(&recosted, &previous));
+	}
+
+	#[test]
+	fn check_is_disabled_without_rate() {
+		let limiter = limiter(0, 1);
+		for _ in 0..10 {
+			assert!(limiter.check("a", "tx_search"));
+		}
+	}
+}
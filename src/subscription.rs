@@ -0,0 +1,246 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex, Weak,
+	},
+	time::Duration,
+};
+use jsonrpsee::{
+	core::Error as RpcError,
+	types::{
+		error::{CallError, TOO_MANY_SUBSCRIPTIONS_CODE},
+		ErrorObject, ErrorObjectOwned,
+	},
+};
+use serde::{Serialize, Serializer};
+use serde_json::Value as JsonValue;
+use tokio::{
+	sync::{broadcast, watch, Notify},
+	time::sleep,
+};
+use crate::metrics::UPSTREAM_SUBSCRIPTIONS;
+use crate::upstream::UpstreamEvents;
+
+/// Event shared by every client of a topic, serialized separately for each of them.
+#[derive(Clone)]
+pub struct Event(Arc<JsonValue>);
+
+impl Serialize for Event {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		self.0.serialize(serializer)
+	}
+}
+
+#[derive(Clone)]
+enum TopicStatus {
+	Connecting,
+	Subscribed,
+	Failed(ErrorObjectOwned),
+}
+
+/// Upstream subscription to one query, its task stops and closes the upstream websocket when the last client leaves.
+struct Topic {
+	events: broadcast::Sender<Event>,
+	status: watch::Receiver<TopicStatus>,
+	stop: Arc<Notify>,
+}
+
+impl Drop for Topic {
+	fn drop(&mut self) {
+		self.stop.notify_one();
+	}
+}
+
+/// Counts a client subscription against the server-wide cap until dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// A client's subscription to a shared topic.
+pub struct TopicSubscription {
+	_slot: Slot,
+	_topic: Arc<Topic>,
+	events: broadcast::Receiver<Event>,
+}
+
+impl TopicSubscription {
+	/// Next event, errors if the client fell too far behind and missed events.
+	pub async fn next(&mut self) -> Result<Event, RpcError> {
+		match self.events.recv().await {
+			Ok(event) => Ok(event),
+			Err(broadcast::error::RecvError::Lagged(n)) => Err(RpcError::Custom(format!("client is not reading events fast enough, missed {}", n))),
+			Err(broadcast::error::RecvError::Closed) => Err(RpcError::Custom("upstream subscription closed".to_string())),
+		}
+	}
+}
+
+/// Holds one upstream subscription per distinct query, given in the canonical form of `Query`, and fans its events out to
+/// every client subscribed to it. Dropped upstream subscriptions are resubscribed with backoff, on whichever upstream
+/// `connect` picks.
+#[derive(Default)]
+pub struct SubscriptionHub {
+	topics: Mutex<HashMap<String, Weak<Topic>>>,
+	active: Arc<AtomicUsize>,
+}
+
+impl SubscriptionHub {
+	const EVENT_BUFFER: usize = 256;
+	const MIN_BACKOFF: Duration = Duration::from_secs(1);
+	const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+	/// Subscribes to the query's topic, `max_subscriptions` caps client subscriptions across all connections, 0 disables it.
+	pub async fn subscribe<F, Fut>(&self, query: &str, max_subscriptions: u32, connect: F) -> Result<TopicSubscription, RpcError>
+	where
+		F: Fn(String) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Result<UpstreamEvents, RpcError>> + Send + 'static,
+	{
+		let slot = Slot(self.active.clone());
+		if self.active.fetch_add(1, Ordering::Relaxed) >= max_subscriptions as usize && max_subscriptions > 0 {
+			return Err(RpcError::Call(CallError::Custom(
+				ErrorObject::owned(TOO_MANY_SUBSCRIPTIONS_CODE, "too many subscriptions on the server", None::<()>)
+			)));
+		}
+		let query = query.to_string();
+		let topic = {
+			let mut topics = self.topics.lock().expect("subscription hub lock poisoned");
+			match topics.get(&query).and_then(Weak::upgrade) {
+				Some(topic) => topic,
+				None => {
+					topics.retain(|_, t| t.strong_count() > 0);
+					let (events, _) = broadcast::channel(Self::EVENT_BUFFER);
+					let (status_tx, status) = watch::channel(TopicStatus::Connecting);
+					let stop = Arc::new(Notify::new());
+					tokio::spawn(run_topic(query.clone(), events.clone(), status_tx, stop.clone(), connect));
+					let topic = Arc::new(Topic { events, status, stop });
+					topics.insert(query, Arc::downgrade(&topic));
+					topic
+				},
+			}
+		};
+		let events = topic.events.subscribe();
+		let mut status = topic.status.clone();
+		loop {
+			match &*status.borrow() {
+				TopicStatus::Connecting => {},
+				TopicStatus::Subscribed => return Ok(TopicSubscription { _slot: slot, _topic: topic, events }),
+				TopicStatus::Failed(e) => return Err(RpcError::Call(CallError::Custom(e.clone()))),
+			}
+			if status.changed().await.is_err() {
+				return Err(RpcError::Custom("upstream subscription closed".to_string()));
+			}
+		}
+	}
+}
+
+async fn run_topic<F, Fut>(
+	query: String,
+	events: broadcast::Sender<Event>,
+	status: watch::Sender<TopicStatus>,
+	stop: Arc<Notify>,
+	connect: F,
+) where
+	F: Fn(String) -> Fut,
+	Fut: Future<Output = Result<UpstreamEvents, RpcError>>,
+{
+	let mut backoff = SubscriptionHub::MIN_BACKOFF;
+	loop {
+		let res = tokio::select! {
+			res = connect(query.clone()) => res,
+			_ = stop.notified() => return,
+		};
+		match res {
+			Ok(mut upstream) => {
+				status.send_replace(TopicStatus::Subscribed);
+				backoff = SubscriptionHub::MIN_BACKOFF;
+				UPSTREAM_SUBSCRIPTIONS.inc();
+				tracing::debug!("subscribed upstream to {}", query);
+				let res = loop {
+					tokio::select! {
+						res = upstream.next() => match res {
+							// fails only when the last client is leaving, the topic is stopped right after
+							Ok(event) => { let _ = events.send(Event(Arc::new(event))); },
+							Err(e) => break Some(e),
+						},
+						_ = stop.notified() => break None,
+					}
+				};
+				UPSTREAM_SUBSCRIPTIONS.dec();
+				match res {
+					Some(e) => tracing::warn!("upstream subscription to {} dropped, resubscribing: {}", query, e),
+					None => {
+						tracing::debug!("unsubscribed upstream from {}", query);
+						return;
+					},
+				}
+			},
+			Err(e) if matches!(*status.borrow(), TopicStatus::Connecting) => {
+				status.send_replace(TopicStatus::Failed(e.into()));
+				return;
+			},
+			Err(e) => tracing::warn!("resubscribing to {} failed, retrying in {:?}: {}", query, backoff, e),
+		}
+		tokio::select! {
+			_ = sleep(backoff) => {},
+			_ = stop.notified() => return,
+		}
+		backoff = (backoff * 2).min(SubscriptionHub::MAX_BACKOFF);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::future::pending;
+	use jsonrpsee::types::error::CallError;
+	use tokio::time::timeout;
+
+	const QUERY: &str = "tm.event = 'NewBlock'";
+
+	fn connect(_: String) -> impl Future<Output = Result<UpstreamEvents, RpcError>> {
+		pending()
+	}
+
+	async fn wait_for_active(hub: &SubscriptionHub, n: usize) {
+		while hub.active.load(Ordering::Relaxed) != n {
+			sleep(Duration::from_millis(1)).await;
+		}
+	}
+
+	#[tokio::test]
+	async fn subscribe_caps_subscriptions_across_connections() {
+		let hub = Arc::new(SubscriptionHub::default());
+		let first = tokio::spawn({
+			let hub = hub.clone();
+			async move { hub.subscribe(QUERY, 1, connect).await.map(|_| ()) }
+		});
+		wait_for_active(&hub, 1).await;
+		match hub.subscribe("tm.event = 'Tx'", 1, connect).await {
+			Err(RpcError::Call(CallError::Custom(e))) => assert_eq!(e.code(), TOO_MANY_SUBSCRIPTIONS_CODE),
+			_ => panic!("expected the subscription cap to be hit"),
+		}
+		assert_eq!(hub.active.load(Ordering::Relaxed), 1);
+		first.abort();
+		let _ = first.await;
+		wait_for_active(&hub, 0).await;
+		assert!(timeout(Duration::from_millis(20), hub.subscribe(QUERY, 1, connect)).await.is_err());
+		assert_eq!(hub.active.load(Ordering::Relaxed), 0);
+	}
+
+	#[tokio::test]
+	async fn subscribe_without_cap() {
+		let hub = Arc::new(SubscriptionHub::default());
+		let first = tokio::spawn({
+			let hub = hub.clone();
+			async move { hub.subscribe(QUERY, 0, connect).await.map(|_| ()) }
+		});
+		wait_for_active(&hub, 1).await;
+		assert!(timeout(Duration::from_millis(20), hub.subscribe(QUERY, 0, connect)).await.is_err());
+		first.abort();
+	}
+}
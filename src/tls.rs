@@ -0,0 +1,174 @@
+use std::{
+	collections::HashMap,
+	fs::{self, File},
+	io::BufReader,
+	net::SocketAddr,
+	sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock},
+	time::{Duration, SystemTime},
+};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use rustls_pemfile::Item;
+use tokio::{
+	io::copy_bidirectional,
+	net::{TcpListener, TcpStream},
+	time::{interval, timeout, MissedTickBehavior},
+};
+use tokio_rustls::{
+	rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig},
+	TlsAcceptor,
+};
+
+lazy_static! {
+	/// Real client address of each forwarded TLS connection, keyed by the local address of its loopback connection.
+	static ref TLS_PEERS: Mutex<HashMap<SocketAddr, SocketAddr>> = Mutex::new(HashMap::new());
+}
+
+/// Set once a TLS terminator exists, from then on only connections it forwarded are accepted.
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+/// Maps the address of a connection forwarded by the TLS terminator back to the client's address. With TLS enabled,
+/// returns `None` for connections made directly to the loopback listener, which would bypass TLS and client auth.
+pub fn peer_addr(remote_addr: SocketAddr) -> Option<SocketAddr> {
+	match TLS_PEERS.lock().expect("tls peers lock poisoned").get(&remote_addr) {
+		Some(peer) => Some(*peer),
+		None if TERMINATING.load(Ordering::Relaxed) => None,
+		None => Some(remote_addr),
+	}
+}
+
+#[derive(Clone)]
+pub struct TlsFiles {
+	pub cert: String,
+	pub key: String,
+	pub client_ca: Option<String>,
+}
+
+impl TlsFiles {
+	fn paths(&self) -> impl Iterator<Item = &String> {
+		[&self.cert, &self.key].into_iter().chain(self.client_ca.as_ref())
+	}
+
+	fn modified(&self) -> Vec<Option<SystemTime>> {
+		self.paths().map(|p| fs::metadata(p).and_then(|m| m.modified()).ok()).collect()
+	}
+
+	pub fn load(&self) -> Result<Arc<ServerConfig>> {
+		let builder = ServerConfig::builder().with_safe_defaults();
+		let mut config = match &self.client_ca {
+			Some(path) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(read_roots(path)?)),
+			None => builder.with_no_client_auth(),
+		}
+			.with_single_cert(read_certs(&self.cert)?, read_private_key(&self.key)?)
+			.map_err(|e| anyhow!("invalid certificate or key: {}", e))?;
+		config.alpn_protocols = vec![b"http/1.1".to_vec()];
+		Ok(Arc::new(config))
+	}
+}
+
+fn read_pem(path: &str) -> Result<Vec<Item>> {
+	let file = File::open(path).map_err(|e| anyhow!("failed reading {}: {}", path, e))?;
+	rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| anyhow!("failed parsing {}: {}", path, e))
+}
+
+pub fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+	let certs = read_pem(path)?.into_iter()
+		.filter_map(|item| match item {
+			Item::X509Certificate(c) => Some(Certificate(c)),
+			_ => None,
+		})
+		.collect::<Vec<Certificate>>();
+	if certs.is_empty() {
+		return Err(anyhow!("no certificate found in {}", path));
+	}
+	Ok(certs)
+}
+
+pub fn read_private_key(path: &str) -> Result<PrivateKey> {
+	read_pem(path)?.into_iter()
+		.find_map(|item| match item {
+			Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+			_ => None,
+		})
+		.ok_or(anyhow!("no private key found in {}", path))
+}
+
+pub fn read_roots(path: &str) -> Result<RootCertStore> {
+	let mut roots = RootCertStore::empty();
+	for cert in read_certs(path)? {
+		roots.add(&cert).map_err(|e| anyhow!("invalid CA certificate in {}: {}", path, e))?;
+	}
+	Ok(roots)
+}
+
+/// Terminates TLS in-process and forwards the plain connection to the RPC server on a loopback address, since the
+/// jsonrpsee server only accepts plain TCP. Other connections to that address are refused, see `peer_addr`.
+pub struct TlsTerminator {
+	pub files: TlsFiles,
+	config: RwLock<Arc<ServerConfig>>,
+	modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl TlsTerminator {
+	const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+	const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+	pub fn new(files: TlsFiles) -> Result<Self> {
+		let terminator = Self {
+			config: RwLock::new(files.load()?),
+			modified: Mutex::new(files.modified()),
+			files,
+		};
+		TERMINATING.store(true, Ordering::Relaxed);
+		Ok(terminator)
+	}
+
+	pub async fn serve(&'static self, listener: TcpListener, backend_addr: SocketAddr) {
+		loop {
+			match listener.accept().await {
+				Ok((stream, peer)) => {
+					tokio::spawn(async move {
+						if let Err(e) = self.forward(stream, peer, backend_addr).await {
+							tracing::debug!("TLS connection from {} failed: {}", peer, e);
+						}
+					});
+				},
+				Err(e) => tracing::error!("failed accepting TLS connection: {}", e),
+			}
+		}
+	}
+
+	async fn forward(&self, stream: TcpStream, peer: SocketAddr, backend_addr: SocketAddr) -> Result<()> {
+		let acceptor = TlsAcceptor::from(self.config.read().expect("tls config lock poisoned").clone());
+		let mut tls = timeout(Self::HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+			.map_err(|_| anyhow!("handshake timed out"))??;
+		let mut backend = TcpStream::connect(backend_addr).await?;
+		let local_addr = backend.local_addr()?;
+		TLS_PEERS.lock().expect("tls peers lock poisoned").insert(local_addr, peer);
+		let res = copy_bidirectional(&mut tls, &mut backend).await;
+		TLS_PEERS.lock().expect("tls peers lock poisoned").remove(&local_addr);
+		res?;
+		Ok(())
+	}
+
+	/// Reloads the certificate, key and client CA when any of the files changes, new connections use the new ones.
+	pub async fn watch(&'static self) {
+		let mut ticker = interval(Self::RELOAD_INTERVAL);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			let modified = self.files.modified();
+			if *self.modified.lock().expect("tls modified lock poisoned") == modified {
+				continue;
+			}
+			match self.files.load() {
+				Ok(config) => {
+					*self.config.write().expect("tls config lock poisoned") = config;
+					*self.modified.lock().expect("tls modified lock poisoned") = modified;
+					tracing::info!("reloaded TLS certificate {}", self.files.cert);
+				},
+				Err(e) => tracing::error!("failed reloading TLS certificate, keeping current one: {}", e),
+			}
+		}
+	}
+}
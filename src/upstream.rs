@@ -0,0 +1,512 @@
+use std::{
+	collections::HashMap,
+	sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc},
+	time::Duration,
+};
+use anyhow::{anyhow, Result};
+use hyper::{
+	body::HttpBody,
+	client::HttpConnector,
+	header::{HeaderName, CONTENT_TYPE},
+	http::HeaderValue,
+	Body, Client, HeaderMap, Method, Request, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use jsonrpsee::{
+	core::{params::ArrayParams, traits::ToRpcParams, Error as RpcError},
+	types::{error::CallError, ErrorObjectOwned, Id, RequestSer},
+};
+use serde_json::{json, Value as JsonValue};
+use soketto::{
+	connection::Receiver as WsReceiver,
+	handshake::{client::{Header, ServerResponse}, Client as WsClient},
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{client::TlsStream, rustls::{ClientConfig, RootCertStore, ServerName}, TlsConnector};
+use tokio_util::{compat::{Compat, TokioAsyncReadCompatExt}, either::Either};
+use crate::config::Config;
+use crate::context::set_call_upstream;
+use crate::metrics::UPSTREAM_ERRORS;
+use crate::tls::{read_certs, read_private_key, read_roots};
+
+/// How connections to upstream nodes are made, shared by every upstream of a pool.
+#[derive(Clone, PartialEq)]
+pub struct UpstreamOptions {
+	pub ca_file: Option<String>,
+	pub client_cert_file: Option<String>,
+	pub client_key_file: Option<String>,
+	pub headers: HashMap<String, String>,
+	pub request_timeout: Duration,
+	pub connect_timeout: Duration,
+}
+
+impl From<&Config> for UpstreamOptions {
+	fn from(config: &Config) -> Self {
+		Self {
+			ca_file: config.upstream_ca_file.clone(),
+			client_cert_file: config.upstream_client_cert_file.clone(),
+			client_key_file: config.upstream_client_key_file.clone(),
+			headers: config.upstream_headers.clone(),
+			request_timeout: Duration::from_millis(config.upstream_request_timeout_ms),
+			connect_timeout: Duration::from_millis(config.upstream_connect_timeout_ms),
+		}
+	}
+}
+
+/// JSON-RPC over HTTP(S) client for upstream nodes.
+#[derive(Clone)]
+pub struct UpstreamClient {
+	pub options: UpstreamOptions,
+	http: Client<HttpsConnector<HttpConnector>>,
+	tls: Arc<ClientConfig>,
+	headers: HeaderMap,
+	next_id: Arc<AtomicU64>,
+}
+
+impl UpstreamClient {
+	const MAX_RESPONSE_BYTES: usize = 10 * 1024 * 1024;
+
+	pub fn new(options: UpstreamOptions) -> Result<Self> {
+		let roots = match &options.ca_file {
+			Some(path) => read_roots(path)?,
+			None => {
+				let mut roots = RootCertStore::empty();
+				let certs = rustls_native_certs::load_native_certs()
+					.map_err(|e| anyhow!("failed loading native root certificates: {}", e))?;
+				roots.add_parsable_certificates(&certs.into_iter().map(|c| c.0).collect::<Vec<_>>());
+				roots
+			},
+		};
+		let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+		let tls = match (&options.client_cert_file, &options.client_key_file) {
+			(Some(cert), Some(key)) => builder
+				.with_single_cert(read_certs(cert)?, read_private_key(key)?)
+				.map_err(|e| anyhow!("invalid upstream client certificate or key: {}", e))?,
+			_ => builder.with_no_client_auth(),
+		};
+		let mut connector = HttpConnector::new();
+		connector.enforce_http(false);
+		connector.set_connect_timeout(Some(options.connect_timeout));
+		let headers = options.headers.iter()
+			.map(|(name, value)| Ok((
+				HeaderName::from_bytes(name.as_bytes()).map_err(|e| anyhow!("invalid upstream header name {}: {}", name, e))?,
+				HeaderValue::from_str(value).map_err(|e| anyhow!("invalid upstream header value for {}: {}", name, e))?,
+			)))
+			.collect::<Result<HeaderMap>>()?;
+		Ok(Self {
+			http: Client::builder().build(
+				HttpsConnectorBuilder::new().with_tls_config(tls.clone()).https_or_http().enable_http1().wrap_connector(connector),
+			),
+			tls: Arc::new(tls),
+			headers,
+			next_id: Arc::new(AtomicU64::new(0)),
+			options,
+		})
+	}
+
+	pub async fn request(&self, url: &str, method: &str, params: ArrayParams) -> Result<JsonValue, RpcError> {
+		let id = Id::Number(self.next_id.fetch_add(1, Ordering::Relaxed));
+		let params = params.to_rpc_params()?;
+		let body = serde_json::to_vec(&RequestSer::borrowed(&id, &method, params.as_deref()))?;
+		let mut req = Request::builder()
+			.method(Method::POST)
+			.uri(url)
+			.header(CONTENT_TYPE, "application/json")
+			.body(Body::from(body))
+			.map_err(|e| RpcError::Transport(e.into()))?;
+		req.headers_mut().extend(self.headers.clone());
+		timeout(self.options.request_timeout, self.send(req)).await.map_err(|_| RpcError::RequestTimeout)?
+	}
+
+	async fn send(&self, req: Request<Body>) -> Result<JsonValue, RpcError> {
+		let res = self.http.request(req).await.map_err(|e| RpcError::Transport(e.into()))?;
+		let status = res.status();
+		let mut body = res.into_body();
+		let mut bytes = Vec::new();
+		while let Some(chunk) = body.data().await {
+			bytes.extend_from_slice(&chunk.map_err(|e| RpcError::Transport(e.into()))?);
+			if bytes.len() > Self::MAX_RESPONSE_BYTES {
+				return Err(RpcError::Transport(anyhow!("response exceeds {} bytes", Self::MAX_RESPONSE_BYTES)));
+			}
+		}
+		let mut res: JsonValue = match serde_json::from_slice(&bytes) {
+			Ok(res) => res,
+			Err(_) if !status.is_success() => return Err(RpcError::Transport(anyhow!("upstream responded with {}", status))),
+			Err(e) => return Err(RpcError::ParseError(e)),
+		};
+		if let Some(err) = res.get_mut("error") {
+			return Err(RpcError::Call(CallError::Custom(serde_json::from_value::<ErrorObjectOwned>(err.take())?)));
+		}
+		if !status.is_success() {
+			return Err(RpcError::Transport(anyhow!("upstream responded with {}", status)));
+		}
+		res.get_mut("result").map(JsonValue::take).ok_or(RpcError::Transport(anyhow!("response has no result")))
+	}
+
+	/// Opens a websocket to the upstream's `/websocket` endpoint and subscribes to `query`.
+	pub async fn subscribe(&self, url: &str, query: &str) -> Result<UpstreamEvents, RpcError> {
+		timeout(self.options.request_timeout, self.subscribe_ws(url, query)).await.map_err(|_| RpcError::RequestTimeout)?
+	}
+
+	async fn subscribe_ws(&self, url: &str, query: &str) -> Result<UpstreamEvents, RpcError> {
+		let uri: Uri = url.parse().map_err(|e| RpcError::Transport(anyhow!("invalid upstream URL {}: {}", url, e)))?;
+		let host = uri.host().ok_or(RpcError::Transport(anyhow!("upstream URL has no host: {}", url)))?;
+		let tls = matches!(uri.scheme_str(), Some("https") | Some("wss"));
+		let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+		let tcp = timeout(self.options.connect_timeout, TcpStream::connect((host, port))).await
+			.map_err(|_| RpcError::Transport(anyhow!("connect timed out")))?
+			.map_err(|e| RpcError::Transport(e.into()))?;
+		let stream = match tls {
+			true => {
+				let server_name = ServerName::try_from(host).map_err(|e| RpcError::Transport(e.into()))?;
+				let tls = TlsConnector::from(self.tls.clone()).connect(server_name, tcp).await
+					.map_err(|e| RpcError::Transport(e.into()))?;
+				Either::Right(tls)
+			},
+			false => Either::Left(tcp),
+		};
+		let host_header = match uri.port() {
+			Some(port) => format!("{}:{}", host, port),
+			None => host.to_string(),
+		};
+		let path = format!("{}/websocket", uri.path().trim_end_matches('/'));
+		let headers = self.options.headers.iter()
+			.map(|(name, value)| Header { name, value: value.as_bytes() })
+			.collect::<Vec<Header>>();
+		let mut client = WsClient::new(stream.compat(), &host_header, &path);
+		client.set_headers(&headers);
+		match client.handshake().await.map_err(|e| RpcError::Transport(e.into()))? {
+			ServerResponse::Accepted { .. } => {},
+			ServerResponse::Redirect { status_code, .. } | ServerResponse::Rejected { status_code } => {
+				return Err(RpcError::Transport(anyhow!("websocket upgrade rejected with {}", status_code)));
+			},
+		}
+		let mut builder = client.into_builder();
+		builder.set_max_message_size(Self::MAX_RESPONSE_BYTES);
+		let (mut sender, receiver) = builder.finish();
+		let req = json!({ "jsonrpc": "2.0", "id": 0, "method": "subscribe", "params": { "query": query } });
+		sender.send_text(req.to_string()).await.map_err(|e| RpcError::Transport(e.into()))?;
+		sender.flush().await.map_err(|e| RpcError::Transport(e.into()))?;
+		let mut events = UpstreamEvents { receiver, buffer: Vec::new() };
+		loop {
+			let mut msg = events.receive().await?;
+			if msg.get("id") != Some(&json!(0)) {
+				continue;
+			}
+			if let Some(err) = msg.get_mut("error") {
+				return Err(RpcError::Call(CallError::Custom(serde_json::from_value::<ErrorObjectOwned>(err.take())?)));
+			}
+			return Ok(events);
+		}
+	}
+}
+
+type WsStream = Compat<Either<TcpStream, TlsStream<TcpStream>>>;
+
+/// Events of a subscription on an upstream websocket, the connection is closed when this is dropped.
+pub struct UpstreamEvents {
+	receiver: WsReceiver<WsStream>,
+	buffer: Vec<u8>,
+}
+
+impl UpstreamEvents {
+	async fn receive(&mut self) -> Result<JsonValue, RpcError> {
+		self.buffer.clear();
+		self.receiver.receive_data(&mut self.buffer).await.map_err(|e| RpcError::Transport(e.into()))?;
+		serde_json::from_slice(&self.buffer).map_err(RpcError::ParseError)
+	}
+
+	/// Next event, or the error the upstream ended the subscription with.
+	pub async fn next(&mut self) -> Result<JsonValue, RpcError> {
+		loop {
+			let mut msg = self.receive().await?;
+			if let Some(err) = msg.get_mut("error") {
+				return Err(RpcError::Call(CallError::Custom(serde_json::from_value::<ErrorObjectOwned>(err.take())?)));
+			}
+			if let Some(event) = msg.get_mut("result").filter(|r| r.get("data").is_some()) {
+				return Ok(event.take());
+			}
+		}
+	}
+}
+
+pub struct Upstream {
+	pub url: String,
+	pub client: UpstreamClient,
+	healthy: AtomicBool,
+	catching_up: AtomicBool,
+	latest_block_height: AtomicU64,
+	earliest_block_height: AtomicU64,
+	probed: AtomicBool,
+	retired: AtomicBool,
+}
+
+impl Upstream {
+	pub fn new(url: &str, client: UpstreamClient) -> Self {
+		Self {
+			url: url.to_string(),
+			client,
+			healthy: AtomicBool::new(true),
+			catching_up: AtomicBool::new(false),
+			latest_block_height: AtomicU64::new(0),
+			earliest_block_height: AtomicU64::new(0),
+			probed: AtomicBool::new(false),
+			retired: AtomicBool::new(false),
+		}
+	}
+
+	pub fn is_healthy(&self) -> bool {
+		self.healthy.load(Ordering::Relaxed)
+	}
+
+	pub fn set_healthy(&self, healthy: bool) {
+		if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+			if healthy {
+				tracing::info!("upstream recovered: {}", self.url);
+			} else {
+				tracing::warn!("upstream marked unhealthy: {}", self.url);
+			}
+		}
+	}
+
+	pub fn is_catching_up(&self) -> bool {
+		self.catching_up.load(Ordering::Relaxed)
+	}
+
+	pub fn latest_block_height(&self) -> u64 {
+		self.latest_block_height.load(Ordering::Relaxed)
+	}
+
+	pub fn earliest_block_height(&self) -> u64 {
+		self.earliest_block_height.load(Ordering::Relaxed)
+	}
+
+	/// Whether the upstream still stores the given height; unknown until the first successful probe.
+	pub fn has_height(&self, height: u64) -> bool {
+		self.earliest_block_height() <= height
+	}
+
+	/// Whether a health probe has succeeded at least once, before that the sync info is unknown.
+	pub fn is_probed(&self) -> bool {
+		self.probed.load(Ordering::Relaxed)
+	}
+
+	pub fn set_sync_info(&self, latest_block_height: u64, earliest_block_height: u64, catching_up: bool) {
+		self.latest_block_height.store(latest_block_height, Ordering::Relaxed);
+		self.earliest_block_height.store(earliest_block_height, Ordering::Relaxed);
+		self.probed.store(true, Ordering::Relaxed);
+		if self.catching_up.swap(catching_up, Ordering::Relaxed) != catching_up {
+			if catching_up {
+				tracing::warn!("upstream is catching up: {}", self.url);
+			} else {
+				tracing::info!("upstream caught up: {}", self.url);
+			}
+		}
+	}
+
+	/// Whether the upstream was removed from the config, which stops its health probes.
+	pub fn is_retired(&self) -> bool {
+		self.retired.load(Ordering::Relaxed)
+	}
+
+	pub fn retire(&self) {
+		self.retired.store(true, Ordering::Relaxed);
+	}
+
+	pub async fn request(&self, method: &str, params: ArrayParams) -> Result<JsonValue, RpcError> {
+		self.client.request(&self.url, method, params).await
+	}
+
+	pub async fn subscribe(&self, query: &str) -> Result<UpstreamEvents, RpcError> {
+		self.client.subscribe(&self.url, query).await
+	}
+}
+
+pub struct UpstreamPool {
+	pub upstreams: Vec<Arc<Upstream>>,
+	pub max_block_lag: u64,
+	next: AtomicUsize,
+}
+
+impl UpstreamPool {
+	/// Builds a pool of `urls`, reusing the upstreams of `previous` with the same URL and client options so they keep
+	/// their health state.
+	pub fn new(urls: &[String], max_block_lag: u64, client: &UpstreamClient, previous: Option<&UpstreamPool>) -> Result<Self> {
+		if urls.is_empty() {
+			return Err(anyhow!("at least one upstream is required"));
+		}
+		Ok(Self {
+			upstreams: urls.iter()
+				.map(|url| match previous.and_then(|p| {
+					p.upstreams.iter().find(|u| &u.url == url && u.client.options == client.options)
+				}) {
+					Some(upstream) => upstream.clone(),
+					None => Arc::new(Upstream::new(url, client.clone())),
+				})
+				.collect(),
+			max_block_lag,
+			next: AtomicUsize::new(0),
+		})
+	}
+
+	pub fn best_block_height(&self) -> u64 {
+		self.upstreams.iter()
+			.filter(|u| u.is_healthy())
+			.map(|u| u.latest_block_height())
+			.max()
+			.unwrap_or_default()
+	}
+
+	/// Highest height reported by any upstream, including ones that have since become unhealthy.
+	pub fn max_known_block_height(&self) -> u64 {
+		self.upstreams.iter().map(|u| u.latest_block_height()).max().unwrap_or_default()
+	}
+
+	/// Whether an upstream is healthy, synced and within `max_block_lag` of the best upstream.
+	pub fn is_in_sync(&self, upstream: &Upstream, best_block_height: u64) -> bool {
+		upstream.is_healthy()
+			&& !upstream.is_catching_up()
+			&& best_block_height.saturating_sub(upstream.latest_block_height()) <= self.max_block_lag
+	}
+
+	/// Whether any in-sync upstream still stores the given height.
+	pub fn has_height(&self, height: u64) -> bool {
+		let best = self.best_block_height();
+		self.upstreams.iter().any(|u| self.is_in_sync(u, best) && u.has_height(height))
+	}
+
+	/// In-sync upstreams in the order they should be tried, round-robin with the ones storing `height` first. Upstreams
+	/// that are unhealthy, catching up or lagging are never tried.
+	pub fn candidates(&self, height: Option<u64>) -> Vec<&Upstream> {
+		let start = self.next.fetch_add(1, Ordering::Relaxed);
+		let best = self.best_block_height();
+		let mut ordered = (0..self.upstreams.len())
+			.map(|i| self.upstreams[(start + i) % self.upstreams.len()].as_ref())
+			.filter(|u| self.is_in_sync(u, best))
+			.collect::<Vec<&Upstream>>();
+		ordered.sort_by_key(|u| !height.is_none_or(|h| u.has_height(h)));
+		ordered
+	}
+
+	pub async fn request(&self, method: &str, params: ArrayParams) -> Result<JsonValue, RpcError> {
+		self.request_at_height(method, params, None).await
+	}
+
+	pub async fn request_at_height(&self, method: &str, params: ArrayParams, height: Option<u64>) -> Result<JsonValue, RpcError> {
+		let mut last_err = None;
+		for upstream in self.candidates(height) {
+			set_call_upstream(&upstream.url);
+			match upstream.request(method, params.clone()).await {
+				Err(e) if is_upstream_failure(&e) => {
+					tracing::warn!("upstream {} failed on {}: {}", upstream.url, method, e);
+					upstream.set_healthy(false);
+					UPSTREAM_ERRORS.with_label_values(&[&upstream.url, method]).inc();
+					last_err = Some(e);
+				},
+				res => return res,
+			}
+		}
+		Err(last_err.unwrap_or_else(|| RpcError::Custom("no upstream in sync".to_string())))
+	}
+
+	pub async fn subscribe(&self, query: &str) -> Result<UpstreamEvents, RpcError> {
+		let mut last_err = None;
+		for upstream in self.candidates(None) {
+			set_call_upstream(&upstream.url);
+			match upstream.subscribe(query).await {
+				Err(e) if is_upstream_failure(&e) => {
+					tracing::warn!("upstream {} failed on subscribe: {}", upstream.url, e);
+					UPSTREAM_ERRORS.with_label_values(&[&upstream.url, "subscribe"]).inc();
+					last_err = Some(e);
+				},
+				res => return res,
+			}
+		}
+		Err(last_err.unwrap_or_else(|| RpcError::Custom("no upstream in sync".to_string())))
+	}
+}
+
+/// Errors caused by the upstream node itself rather than by the request, which warrant trying another node.
+pub fn is_upstream_failure(err: &RpcError) -> bool {
+	!matches!(err, RpcError::Call(_))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pool(n: usize, max_block_lag: u64) -> UpstreamPool {
+		let client = UpstreamClient::new(UpstreamOptions::from(&Config::default())).unwrap();
+		let urls = (0..n).map(|i| format!("http://127.0.0.1:{}", 26657 + i)).collect::<Vec<_>>();
+		UpstreamPool::new(&urls, max_block_lag, &client, None).unwrap()
+	}
+
+	fn urls(candidates: Vec<&Upstream>) -> Vec<&str> {
+		candidates.into_iter().map(|u| u.url.as_str()).collect()
+	}
+
+	#[test]
+	fn is_in_sync_requires_healthy_caught_up_and_within_lag() {
+		let pool = pool(1, 10);
+		let upstream = &pool.upstreams[0];
+		upstream.set_sync_info(90, 1, false);
+		assert!(pool.is_in_sync(upstream, 100));
+		assert!(!pool.is_in_sync(upstream, 101));
+		upstream.set_sync_info(100, 1, true);
+		assert!(!pool.is_in_sync(upstream, 100));
+		upstream.set_sync_info(100, 1, false);
+		upstream.set_healthy(false);
+		assert!(!pool.is_in_sync(upstream, 100));
+	}
+
+	#[test]
+	fn candidates_round_robin_between_in_sync_upstreams() {
+		let pool = pool(3, 10);
+		for upstream in &pool.upstreams {
+			upstream.set_sync_info(100, 1, false);
+		}
+		let first = urls(pool.candidates(None));
+		let second = urls(pool.candidates(None));
+		assert_eq!(first.len(), 3);
+		assert_eq!(first[1..], second[..2]);
+		assert_eq!(first[0], second[2]);
+	}
+
+	#[test]
+	fn candidates_exclude_out_of_sync_upstreams() {
+		let pool = pool(4, 10);
+		pool.upstreams[0].set_sync_info(100, 1, false);
+		pool.upstreams[1].set_sync_info(80, 1, false);
+		pool.upstreams[2].set_sync_info(100, 1, true);
+		pool.upstreams[3].set_sync_info(100, 1, false);
+		pool.upstreams[3].set_healthy(false);
+		for _ in 0..4 {
+			assert_eq!(urls(pool.candidates(None)), vec![pool.upstreams[0].url.as_str()]);
+		}
+		pool.upstreams[0].set_healthy(false);
+		assert!(pool.candidates(None).is_empty());
+	}
+
+	#[test]
+	fn candidates_prefer_upstreams_storing_the_height() {
+		let pool = pool(2, 10);
+		pool.upstreams[0].set_sync_info(100, 50, false);
+		pool.upstreams[1].set_sync_info(100, 1, false);
+		assert!(pool.has_height(10));
+		assert!(!pool.upstreams[0].has_height(10));
+		for _ in 0..2 {
+			assert_eq!(urls(pool.candidates(Some(10))), vec![pool.upstreams[1].url.as_str(), pool.upstreams[0].url.as_str()]);
+		}
+		pool.upstreams[1].set_healthy(false);
+		assert!(!pool.has_height(10));
+		assert!(pool.has_height(50));
+	}
+
+	#[tokio::test]
+	async fn request_fails_without_upstream_in_sync() {
+		let pool = pool(1, 10);
+		pool.upstreams[0].set_sync_info(100, 1, true);
+		let err = pool.request("status", ArrayParams::new()).await.unwrap_err();
+		assert!(err.to_string().contains("no upstream in sync"), "{}", err);
+	}
+}
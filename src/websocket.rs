@@ -0,0 +1,417 @@
+use std::{
+	collections::HashMap,
+	error::Error,
+	future::Future,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+	time::Duration,
+};
+use futures_util::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+use hyper::{
+	header::{CONNECTION, UPGRADE},
+	Body, Request, Response, StatusCode,
+};
+use jsonrpsee::{
+	core::Error as RpcError,
+	types::{
+		error::{CallError, ErrorCode, TOO_MANY_SUBSCRIPTIONS_CODE},
+		ErrorObject, ErrorObjectOwned, Params,
+	},
+};
+use anyhow::anyhow;
+use serde_json::{json, Value as JsonValue};
+use soketto::{
+	connection::{Error as WsError, Receiver, Sender},
+	data::ByteSlice125,
+	handshake::http::{is_upgrade_request, Server},
+};
+use tokio::{
+	sync::{mpsc, oneshot},
+	time::{interval_at, Instant},
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tower::{Layer, Service};
+use crate::auth::api_key_from_request;
+use crate::comet34::{parse_query, Comet34Backend};
+use crate::context::{take_connected_addr, with_call_upstream, RequestContext, Transport};
+use crate::metrics::{SUBSCRIPTIONS, WS_CONNECTIONS};
+use crate::subscription::TopicSubscription;
+
+/// Path websocket upgrades are served on with CometBFT's protocol, `/` keeps jsonrpsee's.
+pub const COMET_WEBSOCKET_PATH: &str = "/websocket";
+
+/// Serves websocket upgrades on `/websocket` with a `CometSession`, must wrap `RefuseConnection` directly since the
+/// remote address is only known right after calling the jsonrpsee service.
+#[derive(Clone)]
+pub struct CometWebsocketLayer {
+	backend: &'static Comet34Backend,
+}
+
+impl CometWebsocketLayer {
+	pub fn new(backend: &'static Comet34Backend) -> Self {
+		Self { backend }
+	}
+}
+
+impl<S> Layer<S> for CometWebsocketLayer {
+	type Service = CometWebsocket<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		CometWebsocket { inner, backend: self.backend }
+	}
+}
+
+#[derive(Clone)]
+pub struct CometWebsocket<S> {
+	inner: S,
+	backend: &'static Comet34Backend,
+}
+
+impl<S> Service<Request<Body>> for CometWebsocket<S>
+where
+	S: Service<Request<Body>, Response = Response<Body>>,
+	S::Response: 'static,
+	S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+	S::Future: Send + 'static,
+{
+	type Response = S::Response;
+	type Error = Box<dyn Error + Send + Sync + 'static>;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+	#[inline]
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx).map_err(Into::into)
+	}
+
+	fn call(&mut self, req: Request<Body>) -> Self::Future {
+		if req.uri().path() != COMET_WEBSOCKET_PATH || !is_upgrade_request(&req) {
+			let fut = self.inner.call(req);
+			return Box::pin(async move { fut.await.map_err(Into::into) });
+		}
+		// jsonrpsee only hands the remote address to `ContextLogger::on_connect`, a plain HTTP request gets it recorded
+		// without jsonrpsee taking over the connection, its response is only used when the connection is refused
+		let mut probe = Request::new(Body::empty());
+		*probe.uri_mut() = req.uri().clone();
+		*probe.headers_mut() = req.headers().clone();
+		probe.headers_mut().remove(UPGRADE);
+		probe.headers_mut().remove(CONNECTION);
+		let probed = self.inner.call(probe);
+		let addr = match take_connected_addr() {
+			Some(addr) => addr,
+			None => return Box::pin(async move { probed.await.map_err(Into::into) }),
+		};
+		drop(probed);
+		let mut server = Server::new();
+		let res = match server.receive_request(&req) {
+			Ok(res) => res,
+			Err(e) => return Box::pin(async move {
+				Ok(Response::builder()
+					.status(StatusCode::BAD_REQUEST)
+					.header(CONNECTION, "close")
+					.body(Body::from(format!("Could not upgrade connection: {}", e)))
+					.expect("valid response"))
+			}),
+		};
+		let context = Arc::new(RequestContext::new(addr.ip(), api_key_from_request(&req), Transport::WebSocket));
+		let backend = self.backend;
+		tokio::spawn(async move {
+			let upgraded = match hyper::upgrade::on(req).await {
+				Ok(upgraded) => upgraded,
+				Err(e) => {
+					tracing::warn!("could not upgrade connection: {}", e);
+					return;
+				},
+			};
+			let mut builder = server.into_builder(BufReader::new(BufWriter::new(upgraded.compat())));
+			builder.set_max_message_size(backend.max_request_body_size_bytes as usize);
+			let (sender, receiver) = builder.finish();
+			CometSession::serve(backend, context, sender, receiver).await;
+		});
+		Box::pin(async move { Ok(res.map(|()| Body::empty())) })
+	}
+}
+
+/// A websocket connection speaking CometBFT's protocol: `subscribe` is answered with `{}` and its events are sent as
+/// responses with the ID of the `subscribe` request followed by `#event`, `unsubscribe` takes the query to stop
+/// receiving events for. Other calls take the same path as HTTP calls, one at a time like CometBFT.
+pub struct CometSession {
+	backend: &'static Comet34Backend,
+	context: Arc<RequestContext>,
+	responses: mpsc::Sender<String>,
+	/// Subscriptions by query as the client sent it, dropping one stops forwarding its events.
+	subscriptions: HashMap<String, oneshot::Sender<()>>,
+}
+
+impl CometSession {
+	const RESPONSE_BUFFER: usize = 64;
+
+	pub async fn serve<T>(backend: &'static Comet34Backend, context: Arc<RequestContext>, sender: Sender<T>, mut receiver: Receiver<T>)
+	where
+		T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	{
+		WS_CONNECTIONS.inc();
+		let (responses, outgoing) = mpsc::channel(Self::RESPONSE_BUFFER);
+		let ping_interval = Duration::from_secs(backend.ws_ping_interval_seconds.max(1).into());
+		let writer = tokio::spawn(write_responses(sender, outgoing, ping_interval));
+		let mut session = Self { backend, context, responses, subscriptions: HashMap::new() };
+		let mut message = Vec::new();
+		loop {
+			message.clear();
+			tokio::select! {
+				res = receiver.receive_data(&mut message) => match res {
+					Ok(_) => {},
+					Err(WsError::Closed) => break,
+					Err(e) => {
+						tracing::debug!("websocket connection failed: {}", e);
+						break;
+					},
+				},
+				_ = backend.drain.websockets_closed() => break,
+			}
+			if !session.handle(&message).await {
+				break;
+			}
+		}
+		// the writer closes the connection once every subscription has stopped sending
+		drop(session);
+		let _ = writer.await;
+		WS_CONNECTIONS.dec();
+	}
+
+	/// Answers a request, returns false once the connection is gone.
+	async fn handle(&mut self, message: &[u8]) -> bool {
+		let request = match serde_json::from_slice::<JsonValue>(message) {
+			Ok(JsonValue::Object(request)) => request,
+			Ok(_) => return self.respond(&JsonValue::Null, Err(ErrorCode::InvalidRequest.into())).await,
+			Err(_) => return self.respond(&JsonValue::Null, Err(ErrorCode::ParseError.into())).await,
+		};
+		let id = request.get("id").cloned().unwrap_or_default();
+		let method = match request.get("method").and_then(JsonValue::as_str) {
+			Some(method) => method,
+			None => return self.respond(&id, Err(ErrorCode::InvalidRequest.into())).await,
+		};
+		let params = request.get("params").map(JsonValue::to_string);
+		let params = Params::new(params.as_deref()).into_owned();
+		let res = match method {
+			"subscribe" => {
+				let started_at = Instant::now();
+				let (res, upstream) = with_call_upstream(self.subscribe(params)).await;
+				self.backend.record(method, Some(&self.context), started_at.elapsed(), res.as_ref().err(), upstream.as_deref(), 0);
+				match res {
+					Ok((query, events)) => {
+						// the reply goes out before the first event
+						let connected = self.respond(&id, Ok(json!({}))).await;
+						let (stop, stopped) = oneshot::channel();
+						tokio::spawn(forward_events(events, query.clone(), event_id(&id), self.responses.clone(), stopped));
+						self.subscriptions.insert(query, stop);
+						return connected;
+					},
+					Err(e) => Err(e),
+				}
+			},
+			"unsubscribe" => {
+				let res = self.unsubscribe(params);
+				self.backend.record(method, Some(&self.context), Duration::ZERO, res.as_ref().err(), None, 0);
+				res
+			},
+			"unsubscribe_all" => {
+				self.subscriptions.clear();
+				self.backend.record(method, Some(&self.context), Duration::ZERO, None, None, 0);
+				Ok(json!({}))
+			},
+			method => match self.backend.routes.get_key_value(method) {
+				Some((method, _)) => self.backend.call(method, params, Some(self.context.clone())).await,
+				None => Err(RpcError::Call(CallError::Custom(ErrorCode::MethodNotFound.into()))),
+			},
+		};
+		self.respond(&id, res.map_err(ErrorObjectOwned::from)).await
+	}
+
+	async fn respond(&self, id: &JsonValue, res: Result<JsonValue, ErrorObjectOwned>) -> bool {
+		let response = match res {
+			Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+			Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+		};
+		self.responses.send(response.to_string()).await.is_ok()
+	}
+
+	async fn subscribe(&mut self, params: Params<'static>) -> Result<(String, TopicSubscription), RpcError> {
+		let query = parse_query(params.clone())?;
+		self.subscriptions.retain(|_, stop| !stop.is_closed());
+		if self.subscriptions.contains_key(&query) {
+			return Err(RpcError::Call(CallError::InvalidParams(anyhow!("already subscribed"))));
+		}
+		let max_subscriptions = self.backend.max_subscriptions_per_connection as usize;
+		if max_subscriptions > 0 && self.subscriptions.len() >= max_subscriptions {
+			return Err(RpcError::Call(CallError::Custom(
+				ErrorObject::owned(TOO_MANY_SUBSCRIPTIONS_CODE, "too many subscriptions on the connection", None::<()>)
+			)));
+		}
+		let events = self.backend.open_subscription(params, Some(&self.context)).await?;
+		Ok((query, events))
+	}
+
+	fn unsubscribe(&mut self, params: Params<'static>) -> Result<JsonValue, RpcError> {
+		let query = parse_query(params)?;
+		self.subscriptions.retain(|_, stop| !stop.is_closed());
+		match self.subscriptions.remove(&query) {
+			Some(_) => Ok(json!({})),
+			None => Err(RpcError::Call(CallError::InvalidParams(anyhow!("subscription not found")))),
+		}
+	}
+}
+
+/// ID CometBFT sends a subscription's events with.
+fn event_id(id: &JsonValue) -> JsonValue {
+	JsonValue::String(match id {
+		JsonValue::String(id) => format!("{}#event", id),
+		id => format!("{}#event", id),
+	})
+}
+
+/// Sends the subscription's events as responses until it is stopped, a client falling too far behind gets an error
+/// response instead and has to subscribe again.
+async fn forward_events(
+	mut events: TopicSubscription,
+	query: String,
+	id: JsonValue,
+	responses: mpsc::Sender<String>,
+	mut stopped: oneshot::Receiver<()>,
+) {
+	SUBSCRIPTIONS.with_label_values(&["subscribe"]).inc();
+	loop {
+		let event = tokio::select! {
+			_ = &mut stopped => break,
+			event = events.next() => event,
+		};
+		let (response, failed) = match event {
+			Ok(event) => {
+				// clients such as Go's rpchttp route events by the query they subscribed with
+				let mut result = serde_json::to_value(&event).unwrap_or_default();
+				if let Some(result) = result.as_object_mut() {
+					result.insert("query".to_string(), query.clone().into());
+				}
+				(json!({ "jsonrpc": "2.0", "id": id, "result": result }), false)
+			},
+			Err(e) => (json!({ "jsonrpc": "2.0", "id": id, "error": ErrorObjectOwned::from(e) }), true),
+		};
+		if responses.send(response.to_string()).await.is_err() || failed {
+			break;
+		}
+	}
+	SUBSCRIPTIONS.with_label_values(&["subscribe"]).dec();
+}
+
+async fn write_responses<T>(mut sender: Sender<T>, mut responses: mpsc::Receiver<String>, ping_interval: Duration)
+where
+	T: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+	loop {
+		let res = tokio::select! {
+			response = responses.recv() => match response {
+				Some(response) => sender.send_text_owned(response).await,
+				None => break,
+			},
+			_ = ping.tick() => sender.send_ping(ByteSlice125::try_from(&[][..]).expect("empty ping")).await,
+		};
+		let res = match res {
+			Ok(()) => sender.flush().await,
+			Err(e) => Err(e),
+		};
+		if let Err(e) = res {
+			tracing::debug!("websocket connection failed: {}", e);
+			return;
+		}
+	}
+	let _ = sender.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{Ipv4Addr, SocketAddr};
+	use soketto::handshake::{self, server::Response as WsResponse, Client, ServerResponse};
+	use tokio::{io::duplex, net::TcpListener, time::timeout};
+	use crate::comet34::Comet34ValidatorInfo;
+	use crate::config::Config;
+
+	async fn accept<T: AsyncRead + AsyncWrite + Unpin>(socket: T) -> (Sender<T>, Receiver<T>) {
+		let mut server = handshake::Server::new(socket);
+		let key = server.receive_request().await.unwrap().key();
+		server.send_response(&WsResponse::Accept { key, protocol: None }).await.unwrap();
+		server.into_builder().finish()
+	}
+
+	async fn receive<T: AsyncRead + AsyncWrite + Unpin>(receiver: &mut Receiver<T>) -> JsonValue {
+		let mut message = Vec::new();
+		timeout(Duration::from_secs(5), receiver.receive_data(&mut message)).await.unwrap().unwrap();
+		serde_json::from_slice(&message).unwrap()
+	}
+
+	async fn send<T: AsyncRead + AsyncWrite + Unpin>(sender: &mut Sender<T>, request: JsonValue) {
+		sender.send_text(request.to_string()).await.unwrap();
+		sender.flush().await.unwrap();
+	}
+
+	/// Upstream answering one subscription and sending a single event on it.
+	async fn upstream() -> SocketAddr {
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (socket, _) = listener.accept().await.unwrap();
+			let (mut sender, mut receiver) = accept(socket.compat()).await;
+			let request = receive(&mut receiver).await;
+			send(&mut sender, json!({ "jsonrpc": "2.0", "id": request["id"], "result": {} })).await;
+			send(&mut sender, json!({
+				"jsonrpc": "2.0",
+				"id": "0#event",
+				"result": {
+					"query": request["params"]["query"],
+					"data": { "type": "tendermint/event/NewBlock", "value": {} },
+					"events": { "tm.event": ["NewBlock"] },
+				},
+			})).await;
+			let _ = receiver.receive_data(&mut Vec::new()).await;
+		});
+		addr
+	}
+
+	#[tokio::test]
+	async fn comet_subscribe_and_unsubscribe() {
+		let config = Config { rpc_addrs: vec![format!("http://{}", upstream().await)], ..Config::default() };
+		let backend = Box::leak(Box::new(Comet34Backend::new(&config, Comet34ValidatorInfo::new().unwrap()).unwrap()));
+		let (client, server) = duplex(64 * 1024);
+		tokio::spawn(async move {
+			let (sender, receiver) = accept(server.compat()).await;
+			let context = Arc::new(RequestContext::new(Ipv4Addr::LOCALHOST.into(), None, Transport::WebSocket));
+			CometSession::serve(backend, context, sender, receiver).await;
+		});
+		let mut client = Client::new(client.compat(), "localhost", "/websocket");
+		assert!(matches!(client.handshake().await.unwrap(), ServerResponse::Accepted { .. }));
+		let (mut sender, mut receiver) = client.into_builder().finish();
+
+		// events carry the query as the client spelled it, not the canonical one sent upstream
+		let query = "tm.event='NewBlock'";
+		send(&mut sender, json!({ "jsonrpc": "2.0", "id": "sub", "method": "subscribe", "params": { "query": query } })).await;
+		assert_eq!(receive(&mut receiver).await, json!({ "jsonrpc": "2.0", "id": "sub", "result": {} }));
+		let event = receive(&mut receiver).await;
+		assert_eq!(event["id"], "sub#event");
+		assert_eq!(event["result"]["query"], query);
+		assert_eq!(event["result"]["data"]["type"], "tendermint/event/NewBlock");
+
+		send(&mut sender, json!({ "jsonrpc": "2.0", "id": 2, "method": "subscribe", "params": [query] })).await;
+		let res = receive(&mut receiver).await;
+		assert_eq!(res["id"], 2);
+		assert_eq!(res["error"]["code"], ErrorCode::InvalidParams.code());
+
+		send(&mut sender, json!({ "jsonrpc": "2.0", "id": 3, "method": "unsubscribe", "params": { "query": query } })).await;
+		assert_eq!(receive(&mut receiver).await, json!({ "jsonrpc": "2.0", "id": 3, "result": {} }));
+
+		send(&mut sender, json!({ "jsonrpc": "2.0", "id": 4, "method": "unsubscribe", "params": { "query": query } })).await;
+		let res = receive(&mut receiver).await;
+		assert_eq!(res["id"], 4);
+		assert_eq!(res["error"]["message"], "subscription not found");
+	}
+}